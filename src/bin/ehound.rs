@@ -0,0 +1,603 @@
+use chrono::{DateTime, Utc};
+use ehound::{
+    config::{self, Config, Overrides},
+    filter::{ChapterFilter, Range},
+    library::{Book, BookId, BookName, BookStatus, Library},
+    migrate,
+    notify::{Email, Hook, Webhook},
+    storage::{self, Query},
+    update::{AddMode, BookKind, Kept, Manager},
+    verify::Report,
+};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fmt,
+    path::PathBuf,
+    time::Duration,
+};
+use structopt::StructOpt;
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+/// Downloads manga and novels and keeps them up to date
+#[derive(Debug, StructOpt)]
+#[structopt(name = "ehound")]
+struct Opt {
+    /// Library to use, an SQLite database when it ends in .db
+    #[structopt(long, global = true, parse(from_os_str))]
+    library:     Option<PathBuf>,
+    /// Config file to read instead of the one in the XDG config directory
+    #[structopt(long, global = true, parse(from_os_str))]
+    config:      Option<PathBuf>,
+    /// Where downloads and everything kept between runs go
+    #[structopt(long, global = true, parse(from_os_str))]
+    cache:       Option<PathBuf>,
+    #[structopt(long, global = true)]
+    user_agent:  Option<String>,
+    /// Least seconds between two requests to a site
+    #[structopt(long, global = true)]
+    delay:       Option<f64>,
+    /// Downloads running at once
+    #[structopt(long, global = true)]
+    concurrency: Option<usize>,
+    /// Prints JSON instead of text
+    #[structopt(long, global = true)]
+    json:        bool,
+    #[structopt(subcommand)]
+    cmd:         Cmd,
+}
+#[derive(Debug, StructOpt)]
+enum Cmd {
+    /// Adds the book at the url and downloads its chapters
+    Add {
+        url:     String,
+        #[structopt(long)]
+        name:    Option<String>,
+        /// Only keeps track of the book, nothing is downloaded
+        #[structopt(long)]
+        track:   bool,
+        /// Shows what would be added without adding it
+        #[structopt(long)]
+        preview: bool,
+        /// Chapters to download: "10-20", "latest 5", "from 100"
+        #[structopt(long)]
+        range:   Option<Range>,
+        /// Volume to download, may be given more than once
+        #[structopt(long = "volume")]
+        volumes: Vec<u16>,
+        /// Skips chapters whose title matches the pattern
+        #[structopt(long)]
+        exclude: Vec<String>,
+    },
+    /// Lists the books of the library
+    List {
+        /// Part of the name
+        name:   Option<String>,
+        #[structopt(long)]
+        status: Option<BookStatus>,
+    },
+    /// Shows what's known about a book
+    Info { book: String },
+    /// Looks for new chapters of the book, or of every book
+    Update { book: Option<String> },
+    /// Downloads the chapters of the book that are missing
+    Download {
+        book:    String,
+        /// Only these chapters instead of the ones the book's filter picks
+        range:   Option<Range>,
+        /// Jobs run at once, `workers` of the config when unset
+        #[structopt(long)]
+        workers: Option<usize>,
+    },
+    /// Takes the book out of the library, its files are left on disk
+    Remove { book: String },
+    /// Gives the book another name, its files are left where they are
+    Rename { book: String, name: String },
+    /// Writes the library, or one of its books, to another file
+    Export {
+        #[structopt(parse(from_os_str))]
+        to:   PathBuf,
+        #[structopt(long)]
+        book: Option<String>,
+    },
+    /// Checks the downloaded files
+    Verify {
+        /// Downloads the broken pages again
+        #[structopt(long)]
+        repair: bool,
+    },
+    /// Keeps checking the books for new chapters until stopped
+    Daemon,
+    /// Upgrades a JSON library written by an older version and moves its
+    /// files to match
+    Migrate {
+        /// Only shows what would change
+        #[structopt(long)]
+        dry_run: bool,
+    },
+    /// Shows or changes the settings
+    Config(ConfigCmd),
+}
+#[derive(Debug, StructOpt)]
+enum ConfigCmd {
+    Show,
+    /// interval.{ongoing,unknown,hiatus,completed} in hours,
+    /// interval.spacing in seconds, notify.{webhook,command,email,batch},
+    /// book.<book>.interval in hours, book.<book>.range. Anything else goes
+    /// to the config file as it is, like user_agent, limits.rate or
+    /// sites.<domain>.next, with JSON values. Settings other than the
+    /// book's own are used from the next run on.
+    Set {
+        key:   String,
+        value: String,
+    },
+    Unset {
+        key: String,
+    },
+}
+
+#[derive(Serialize)]
+struct Summary {
+    id:       BookId,
+    name:     BookName,
+    status:   BookStatus,
+    kind:     BookKind,
+    chapters: usize,
+    updated:  Option<DateTime<Utc>>,
+}
+#[derive(Serialize)]
+struct Info {
+    #[serde(flatten)]
+    summary:  Summary,
+    aliases:  Vec<BookName>,
+    source:   String,
+    /// Lowest and highest chapter downloaded
+    range:    Option<(u16, u16)>,
+    reading:  u16,
+    filter:   ChapterFilter,
+    meta:     BTreeMap<String, String>,
+    checked:  Option<DateTime<Utc>>,
+    next:     Option<DateTime<Utc>>,
+    interval: Duration,
+}
+#[derive(Serialize)]
+struct Verified {
+    #[serde(flatten)]
+    report: Report,
+    fixed:  Option<usize>,
+}
+/// Anything printed, along with what was done in a few words
+#[derive(Serialize)]
+struct Done {
+    done: String,
+}
+#[derive(Serialize)]
+struct List(Vec<Summary>);
+
+#[tokio::main]
+async fn main() {
+    let opt = Opt::from_args();
+    if let Err(e) = run(opt).await {
+        eprintln!("ehound: {}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn run(opt: Opt) -> Result<()> {
+    let delay = match opt.delay {
+        Some(secs) if !secs.is_finite() || secs < 0. => {
+            return Err(format!("{} is not a delay", secs).into())
+        }
+        delay => delay.map(Duration::from_secs_f64),
+    };
+    let config = Config::load(Overrides {
+        file: opt.config,
+        cache: opt.cache,
+        library: opt.library,
+        user_agent: opt.user_agent,
+        delay,
+        concurrency: opt.concurrency,
+    })?;
+    let mut manager = Manager::open(config).await?;
+    let json = opt.json;
+    match opt.cmd {
+        Cmd::Add {
+            url,
+            name,
+            track,
+            preview,
+            range,
+            volumes,
+            exclude,
+        } => {
+            if preview {
+                return print(json, &manager.preview(&url).await?);
+            }
+            let mut filter = ChapterFilter {
+                range,
+                volumes: volumes.into_iter().collect(),
+                ..Default::default()
+            };
+            for pattern in &exclude {
+                filter = filter.exclude(pattern)?;
+            }
+            let mode = match track {
+                true => AddMode::Track,
+                false => AddMode::Download,
+            };
+            let name = name.map(BookName::from);
+            let report = manager.add_book(name, url.into(), mode, filter).await?;
+            print(json, &report)?;
+            if report.kept == Kept::RolledBack {
+                return Err(
+                    format!("{} wasn't added", report.name.as_str()).into()
+                );
+            }
+            Ok(())
+        }
+        Cmd::List { name, status } => {
+            let query = Query {
+                name,
+                status,
+                ..Default::default()
+            };
+            let books = manager
+                .library()
+                .books
+                .values()
+                .filter(|b| query.matches(b))
+                .map(summary)
+                .collect();
+            print(json, &List(books))
+        }
+        Cmd::Info { book } => {
+            let book = find(manager.library(), &book)?;
+            let watch = manager.watch();
+            let watch = watch.lock().await;
+            let checks = watch.checks(&book.id).cloned().unwrap_or_default();
+            let chapters = book.chapters();
+            print(json, &Info {
+                summary:  summary(book),
+                aliases:  book.aliases.clone(),
+                source:   book.index.location.clone(),
+                range:    chapters
+                    .keys()
+                    .next()
+                    .zip(chapters.keys().next_back())
+                    .map(|(a, b)| (*a, *b)),
+                reading:  book.pos,
+                filter:   book.filter.clone(),
+                meta:     book.meta.clone(),
+                checked:  checks.last,
+                next:     checks.next,
+                interval: watch.interval(book),
+            })
+        }
+        Cmd::Update { book } => {
+            let report = match book {
+                Some(book) => {
+                    let id = find(manager.library(), &book)?.id.clone();
+                    manager.refresh_books(&[id]).await
+                }
+                None => manager.refresh().await,
+            };
+            // Nothing is left waiting for a batch, the process is about to end
+            manager.notify(true).await;
+            print(json, &report)
+        }
+        Cmd::Download {
+            book,
+            range,
+            workers,
+        } => {
+            let id = find(manager.library(), &book)?.id.clone();
+            let workers = workers.unwrap_or(manager.config().workers);
+            let jobs = manager.download(&id, range, workers).await?;
+            done(json, format!("{} jobs done for {}", jobs, id))
+        }
+        Cmd::Remove { book } => {
+            let id = find(manager.library(), &book)?.id.clone();
+            manager.remove_book(&id).await;
+            done(json, format!("Removed {}", id))
+        }
+        Cmd::Rename { book, name } => {
+            let id = find(manager.library(), &book)?.id.clone();
+            manager.rename_book(&id, name.into()).await;
+            done(json, format!("Renamed {}", id))
+        }
+        Cmd::Export { to, book } => {
+            let mut lib = manager.library().clone();
+            if let Some(book) = book {
+                let id = find(&lib, &book)?.id.clone();
+                lib.books.retain(|b, _| *b == id);
+            }
+            storage::open(&to)?.save(&lib)?;
+            let exported = lib.books.len();
+            done(json, format!("Exported {} books to {:?}", exported, to))
+        }
+        Cmd::Verify { repair } => {
+            let report = manager.verify();
+            let fixed = match repair {
+                true => Some(manager.repair(&report).await),
+                false => None,
+            };
+            print(json, &Verified { report, fixed })
+        }
+        Cmd::Daemon => {
+            manager
+                .daemon(async {
+                    tokio::signal::ctrl_c().await.ok();
+                })
+                .await;
+            done(json, "Stopped".to_string())
+        }
+        Cmd::Migrate { dry_run } => {
+            let location = manager.config().library();
+            let migration = migrate::LIBRARY.migrate_file(&location, dry_run)?;
+            let mut text = format!(
+                "{:?} from version {} to {}",
+                location, migration.from, migration.to
+            );
+            for change in &migration.changes {
+                text += &format!("\n  {}", change);
+            }
+            done(json, text)
+        }
+        Cmd::Config(cmd) => configure(&mut manager, json, cmd).await,
+    }
+}
+
+async fn configure(
+    manager: &mut Manager, json: bool, cmd: ConfigCmd,
+) -> Result<()> {
+    let (key, value) = match cmd {
+        ConfigCmd::Show => return show(manager, json).await,
+        ConfigCmd::Set { key, value } => (key, Some(value)),
+        ConfigCmd::Unset { key } => (key, None),
+    };
+    let hours = |v: &str| -> Result<Duration> {
+        Ok(Duration::from_secs_f64(v.parse::<f64>()? * 3600.))
+    };
+    let seconds =
+        |v: &str| -> Result<Duration> { Ok(Duration::from_secs(v.parse()?)) };
+    let path = key.split('.').collect::<Vec<_>>();
+    match (path.as_slice(), value.as_deref()) {
+        (["book", book, setting], value) => {
+            let id = find(manager.library(), book)?.id.clone();
+            match *setting {
+                "interval" => {
+                    let interval = value.map(hours).transpose()?;
+                    manager.set_interval(&id, interval).await;
+                }
+                "range" => {
+                    let filter = ChapterFilter {
+                        range: value.map(str::parse).transpose()?,
+                        ..find(manager.library(), book)?.filter.clone()
+                    };
+                    manager.set_filter(&id, filter).await;
+                }
+                _ => return Err(format!("Unknown setting {}", key).into()),
+            }
+        }
+        (["interval", status], value) => {
+            let value = match *status {
+                "ongoing" | "unknown" | "hiatus" | "completed" => {
+                    value.map(hours).transpose()?
+                }
+                "spacing" => value.map(seconds).transpose()?,
+                _ => return Err(format!("Unknown setting {}", key).into()),
+            };
+            let value = value.map(serde_json::to_value).transpose()?;
+            set(manager.config(), &format!("intervals.{}", status), value)?;
+        }
+        (["notify", setting], value) => {
+            let value = match *setting {
+                "webhook" => value
+                    .map(|url| {
+                        serde_json::to_value(Webhook {
+                            url:     url.to_string(),
+                            headers: BTreeMap::new(),
+                        })
+                    })
+                    .transpose()?,
+                "command" => value
+                    .map(|line| {
+                        let mut words = line.split_whitespace().map(String::from);
+                        serde_json::to_value(Hook {
+                            program: words.next().unwrap_or_default(),
+                            args:    words.collect(),
+                        })
+                    })
+                    .transpose()?,
+                // Given as JSON, there's too much of it for anything else
+                "email" => value
+                    .map(|email| {
+                        serde_json::from_str::<Email>(email)
+                            .and_then(serde_json::to_value)
+                    })
+                    .transpose()?,
+                "batch" => value
+                    .map(|minutes| -> Result<_> {
+                        let minutes = minutes.parse::<u64>()?;
+                        let batch = Duration::from_secs(minutes * 60);
+                        Ok(serde_json::to_value(batch)?)
+                    })
+                    .transpose()?,
+                _ => return Err(format!("Unknown setting {}", key).into()),
+            };
+            set(manager.config(), &key, value)?;
+        }
+        (["interval", ..], _) | (["notify", ..], _) | (["book", ..], _) => {
+            return Err(format!("Unknown setting {}", key).into())
+        }
+        _ => {
+            let location = config_file(manager.config())?;
+            config::edit(&location, &key, value.as_deref())?;
+        }
+    }
+    done(json, format!("Set {}", key))
+}
+
+async fn show(manager: &Manager, json: bool) -> Result<()> {
+    let config = manager.config();
+    match json {
+        true => println!("{}", serde_json::to_string_pretty(config)?),
+        false => {
+            let hours = |d: Duration| d.as_secs_f64() / 3600.;
+            let i = &config.intervals;
+            println!("interval.ongoing = {}", hours(i.ongoing));
+            println!("interval.unknown = {}", hours(i.unknown));
+            println!("interval.hiatus = {}", hours(i.hiatus));
+            println!("interval.completed = {}", hours(i.completed));
+            println!("interval.spacing = {}", i.spacing.as_secs());
+            let n = &config.notify;
+            if let Some(webhook) = &n.webhook {
+                println!("notify.webhook = {}", webhook.url);
+            }
+            if let Some(hook) = &n.command {
+                println!(
+                    "notify.command = {} {}",
+                    hook.program,
+                    hook.args.join(" ")
+                );
+            }
+            if let Some(email) = &n.email {
+                println!("notify.email = {}", serde_json::to_string(email)?);
+            }
+            println!("notify.batch = {}", n.batch.as_secs() / 60);
+            let mut rest = serde_json::to_value(config)?;
+            if let Some(rest) = rest.as_object_mut() {
+                rest.remove("intervals");
+                rest.remove("notify");
+            }
+            flatten("", &rest);
+        }
+    }
+    Ok(())
+}
+
+/// Sets the dotted `key` of the config file to `value`, or removes it
+fn set(
+    config: &Config, key: &str, value: Option<serde_json::Value>,
+) -> Result<()> {
+    let value = value.map(|v| v.to_string());
+    Ok(config::edit(&config_file(config)?, key, value.as_deref())?)
+}
+
+fn config_file(config: &Config) -> Result<PathBuf> {
+    Ok(config
+        .location
+        .clone()
+        .ok_or("There's no config file, HOME isn't set")?)
+}
+
+/// Prints the leaves of `value` as dotted keys
+fn flatten(key: &str, value: &serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (k, v) in map {
+                match key {
+                    "" => flatten(k, v),
+                    key => flatten(&format!("{}.{}", key, k), v),
+                }
+            }
+        }
+        serde_json::Value::Null => {}
+        value => println!("{} = {}", key, value),
+    }
+}
+
+fn find<'a>(lib: &'a Library, name: &str) -> Result<&'a Book> {
+    lib.find(name)
+        .ok_or_else(|| format!("No book called {:?}", name).into())
+}
+
+fn summary(book: &Book) -> Summary {
+    Summary {
+        id:       book.id.clone(),
+        name:     book.name.clone(),
+        status:   book.status,
+        kind:     BookKind::from(book.visual()),
+        chapters: book.chapters().len(),
+        updated:  book.updated,
+    }
+}
+
+fn print<T: Serialize + fmt::Display>(json: bool, value: &T) -> Result<()> {
+    match json {
+        true => println!("{}", serde_json::to_string_pretty(value)?),
+        false => println!("{}", value),
+    }
+    Ok(())
+}
+
+fn done(json: bool, done: String) -> Result<()> { print(json, &Done { done }) }
+
+impl fmt::Display for Done {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.done)
+    }
+}
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({}) {:?}, {:?}, {} chapters",
+            self.name.as_str(),
+            self.id,
+            self.kind,
+            self.status,
+            self.chapters
+        )
+    }
+}
+impl fmt::Display for List {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|book| writeln!(f, "{}", book))
+    }
+}
+impl fmt::Display for Info {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.summary)?;
+        writeln!(f, "source: {}", self.source)?;
+        if !self.aliases.is_empty() {
+            let aliases = self.aliases.iter().map(|a| a.as_str());
+            writeln!(f, "also: {}", aliases.collect::<Vec<_>>().join(", "))?;
+        }
+        if let Some((first, last)) = self.range {
+            writeln!(f, "chapters: {} to {}", first, last)?;
+        }
+        writeln!(f, "reading: {}", self.reading)?;
+        if let Some(range) = self.filter.range {
+            writeln!(f, "range: {}", range)?;
+        }
+        for (key, value) in &self.meta {
+            writeln!(f, "{}: {}", key, value)?;
+        }
+        let hours = self.interval.as_secs_f64() / 3600.;
+        write!(f, "checked every {:.1}h", hours)?;
+        if let Some(next) = self.next {
+            write!(f, ", next at {}", next)?;
+        }
+        Ok(())
+    }
+}
+impl fmt::Display for Verified {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for problem in &self.report.problems {
+            writeln!(f, "{}", problem)?;
+        }
+        write!(
+            f,
+            "{} pages checked, {} problems",
+            self.report.checked,
+            self.report.problems.len()
+        )?;
+        if let Some(fixed) = self.fixed {
+            write!(f, ", {} fixed", fixed)?;
+        }
+        Ok(())
+    }
+}
@@ -1,6 +1,11 @@
 #![feature(destructuring_assignment)]
 
-use ehound::{update::Manager, TEST};
+use ehound::{
+    config::{Config, Overrides},
+    events::Event,
+    update::{AddMode, Manager},
+    TEST,
+};
 use piston_window::{
     clear,
     AdvancedWindow,
@@ -19,6 +24,78 @@ use piston_window::{
 };
 use sdl2::video::FullscreenType;
 use sdl2_window::Sdl2Window;
+use tokio::sync::{broadcast, mpsc};
+
+/// What the window asks of the manager running in the background
+enum Command {
+    Refresh,
+    Add(String),
+}
+
+/// What the window shows of the work going on in the background
+#[derive(Default)]
+struct Progress {
+    running:  bool,
+    chapters: usize,
+    pages:    usize,
+    errors:   usize,
+    /// Events that came in faster than the window could take them
+    missed:   u64,
+    /// The background thread is gone, nothing sent to it is done
+    stopped:  bool,
+}
+
+impl Progress {
+    fn update(&mut self, event: &Event) {
+        match event {
+            Event::BookDiscovered { .. } | Event::ChapterQueued { .. } => {
+                self.running = true
+            }
+            Event::PageDownloaded { .. } => self.pages += 1,
+            Event::ChapterCompleted { .. } => self.chapters += 1,
+            Event::Error { book, error } => {
+                self.errors += 1;
+                match book {
+                    Some(book) => eprintln!("{}: {}", book, error),
+                    None => eprintln!("{}", error),
+                }
+            }
+            Event::Finished { .. } => self.running = false,
+            Event::RateLimited { .. } => {}
+        }
+    }
+
+    fn send(
+        &mut self, commands: &mpsc::UnboundedSender<Command>, command: Command,
+    ) {
+        match commands.send(command) {
+            Ok(()) => self.running = true,
+            Err(_) => {
+                eprintln!("The downloads have stopped, restart to go on");
+                self.stopped = true;
+            }
+        }
+    }
+
+    fn title(&self) -> String {
+        let state = match (self.stopped, self.running) {
+            (true, _) => "stopped",
+            (false, true) => "downloading",
+            (false, false) => "idle",
+        };
+        let mut title = format!(
+            "Downloader - {}, {} chapters, {} pages",
+            state, self.chapters, self.pages
+        );
+        if self.errors > 0 {
+            title += &format!(", {} errors", self.errors);
+        }
+        if self.missed > 0 {
+            title += &format!(", {} events missed", self.missed);
+        }
+        title
+    }
+}
 
 #[allow(unused_variables, unused_assignments)]
 #[tokio::main]
@@ -42,9 +119,30 @@ async fn main() {
     #[allow(unused_mut)]
     let mut ctx = window.create_texture_context();
 
-    let mut manager = Manager::default();
+    let config =
+        Config::load(Overrides::default()).expect("Couldn't load the config");
+    let (commands, mut events) = background(config);
+    let mut progress = Progress::default();
+    let mut title = String::new();
 
     while let Some(e) = window.next() {
+        loop {
+            match events.try_recv() {
+                Ok(event) => progress.update(&event),
+                Err(broadcast::error::TryRecvError::Lagged(n)) => {
+                    progress.missed += n
+                }
+                Err(broadcast::error::TryRecvError::Closed) => {
+                    progress.stopped = true;
+                    break;
+                }
+                Err(broadcast::error::TryRecvError::Empty) => break,
+            }
+        }
+        if progress.title() != title {
+            title = progress.title();
+            window.set_title(title.clone());
+        }
         window.draw_2d(&e, |c, g, _device| {
             clear([0.0; 4], g);
             // app.draw(c, g, None);
@@ -62,9 +160,9 @@ async fn main() {
         if let Some(button) = e.press_args() {
             if let Button::Keyboard(key) = button {
                 match key {
-                    Key::R => println!("{}", manager.refresh().await),
+                    Key::R => progress.send(&commands, Command::Refresh),
                     Key::E => {
-                        manager.add_book(None, TEST.to_string().into()).await
+                        progress.send(&commands, Command::Add(TEST.to_string()))
                     }
                     Key::Q => break,
                     Key::F | Key::F12 => fullscreen(&mut window),
@@ -74,6 +172,48 @@ async fn main() {
         }
     }
 }
+/// Runs the manager on its own thread so downloads never block the window.
+/// Parsed pages aren't `Send`, so the manager is created on that thread and
+/// never leaves it.
+fn background(
+    config: Config,
+) -> (mpsc::UnboundedSender<Command>, broadcast::Receiver<Event>) {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let (events_tx, events_rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("Couldn't start the download runtime")
+            .block_on(async move {
+                let mut manager = Manager::open(config)
+                    .await
+                    .expect("Couldn't load the library");
+                events_tx.send(manager.subscribe()).unwrap_or_default();
+                while let Some(command) = rx.recv().await {
+                    match command {
+                        Command::Refresh => {
+                            manager.refresh().await;
+                        }
+                        Command::Add(url) => {
+                            // Failures reach the window as events
+                            manager
+                                .add_book(
+                                    None,
+                                    url.into(),
+                                    AddMode::Download,
+                                    Default::default(),
+                                )
+                                .await
+                                .ok();
+                        }
+                    }
+                }
+            })
+    });
+    let events = events_rx.recv().expect("Couldn't load the library");
+    (tx, events)
+}
 fn fullscreen(window: &mut PistonWindow<Sdl2Window>) {
     match window.window.window.fullscreen_state() {
         FullscreenType::Off => {
@@ -0,0 +1,205 @@
+use crate::library::digest;
+use chrono::{DateTime, Utc};
+use reqwest::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+/// Fetched pages stored zstd compressed under the hash of their contents,
+/// with an index by URL of which page it returned when
+#[derive(Debug, Clone)]
+pub struct PageCache {
+    pub dir: PathBuf,
+}
+/// Where pages and images come from
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Mode {
+    /// Downloads everything, keeping only the HTML pages
+    Online,
+    /// Never touches the network, misses are errors
+    Offline,
+    /// Downloads everything and keeps every response with its status and
+    /// headers, images included
+    Record,
+}
+/// Where to find the HTML a `Source` was built from
+#[derive(
+    Default,
+    Debug,
+    Clone,
+    Eq,
+    PartialEq,
+    Ord,
+    PartialOrd,
+    Hash,
+    Serialize,
+    Deserialize,
+)]
+pub struct PageRef {
+    pub url:     String,
+    pub fetched: Option<DateTime<Utc>>,
+    pub sha256:  String,
+    /// Content-Type of the response, `None` for HTML pages
+    #[serde(default)]
+    pub mime:    Option<String>,
+    /// Status of the response, kept in record mode
+    #[serde(default)]
+    pub status:  Option<u16>,
+    /// Headers of the response, kept in record mode
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+
+impl Default for Mode {
+    fn default() -> Self { Mode::Online }
+}
+
+impl PageCache {
+    const LEVEL: i32 = 9;
+
+    /// The page cache kept in the `cache` directory
+    pub fn new(cache: &Path) -> Self {
+        Self {
+            dir: cache.join("pages"),
+        }
+    }
+
+    /// Stores the page unless identical contents are already cached and
+    /// records the fetch in the index
+    pub fn put(&self, url: &str, html: &str) -> io::Result<PageRef> {
+        self.put_raw(url, html.as_bytes(), None)
+    }
+
+    /// Same as put() for any response body
+    pub fn put_raw(
+        &self, url: &str, data: &[u8], mime: Option<String>,
+    ) -> io::Result<PageRef> {
+        self.store(
+            PageRef {
+                url: url.to_string(),
+                mime,
+                ..Default::default()
+            },
+            data,
+        )
+    }
+
+    /// Same as put_raw() keeping the whole response, used in record mode
+    pub fn put_response(
+        &self, url: &str, status: u16, headers: &HeaderMap, data: &[u8],
+    ) -> io::Result<PageRef> {
+        let text = |v: &HeaderValue| v.to_str().ok().map(str::to_string);
+        self.store(
+            PageRef {
+                url: url.to_string(),
+                mime: headers.get(CONTENT_TYPE).and_then(text),
+                status: Some(status),
+                headers: headers
+                    .iter()
+                    .filter_map(|(k, v)| Some((k.to_string(), text(v)?)))
+                    .collect(),
+                ..Default::default()
+            },
+            data,
+        )
+    }
+
+    fn store(&self, mut page: PageRef, data: &[u8]) -> io::Result<PageRef> {
+        page.fetched = Some(Utc::now());
+        page.sha256 = digest(data);
+        let path = self.path(&page.sha256);
+        if !path.exists() {
+            path.parent().map(std::fs::create_dir_all).transpose()?;
+            let tmp = path.with_extension("tmp");
+            std::fs::write(&tmp, zstd::encode_all(data, Self::LEVEL)?)?;
+            std::fs::rename(tmp, &path)?;
+        }
+        self.record(&page)?;
+        Ok(page)
+    }
+
+    pub fn get(&self, page: &PageRef) -> io::Result<String> {
+        String::from_utf8(self.get_raw(page)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn get_raw(&self, page: &PageRef) -> io::Result<Vec<u8>> {
+        zstd::decode_all(File::open(self.path(&page.sha256))?)
+    }
+
+    /// The latest cached response for the URL, a `NotFound` error when it
+    /// was never fetched
+    pub fn replay(&self, url: &str) -> io::Result<(PageRef, Vec<u8>)> {
+        let page = self.latest(url).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} isn't in the page cache", url),
+            )
+        })?;
+        let data = self.get_raw(&page)?;
+        Ok((page, data))
+    }
+
+    /// The most recent fetch of the URL that's still in the cache, only the
+    /// URL's own part of the index is read
+    pub fn latest(&self, url: &str) -> Option<PageRef> {
+        let index = File::open(self.index(url)).ok()?;
+        BufReader::new(index)
+            .lines()
+            .filter_map(|l| serde_json::from_str::<PageRef>(&l.ok()?).ok())
+            .filter(|p| p.url == url && self.path(&p.sha256).exists())
+            .max_by_key(|p| p.fetched)
+    }
+
+    /// Appends the fetch to the index of its URL
+    fn record(&self, page: &PageRef) -> io::Result<()> {
+        let path = self.index(&page.url);
+        path.parent().map(std::fs::create_dir_all).transpose()?;
+        let mut index =
+            File::with_options().create(true).append(true).open(path)?;
+        writeln!(index, "{}", serde_json::to_string(page)?)
+    }
+
+    fn index(&self, url: &str) -> PathBuf {
+        let hash = digest(url.as_bytes());
+        self.dir
+            .join("index")
+            .join(&hash[..2])
+            .join(format!("{}.jsonl", hash))
+    }
+
+    fn path(&self, sha256: &str) -> PathBuf {
+        self.dir
+            .join(&sha256[..2])
+            .join(format!("{}.html.zst", sha256))
+    }
+}
+
+#[test]
+fn page_cache() {
+    let cache = PageCache {
+        dir: std::env::temp_dir()
+            .join(format!("ehound-pages-{}", std::process::id())),
+    };
+    let url = "https://example.com/book/chapter-1";
+    let first = cache.put(url, "<html><p>one</p></html>").unwrap();
+    let again = cache.put(url, "<html><p>one</p></html>").unwrap();
+    assert_eq!(first.sha256, again.sha256);
+    let second = cache.put(url, "<html><p>two</p></html>").unwrap();
+    assert_eq!(cache.get(&first).unwrap(), "<html><p>one</p></html>");
+    assert_eq!(cache.latest(url), Some(second));
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_TYPE, "image/png".parse().unwrap());
+    let png = "https://example.com/0.png";
+    let png = cache.put_response(png, 200, &headers, b"\x89PNG").unwrap();
+    let (replayed, _) = cache.replay("https://example.com/0.png").unwrap();
+    assert_eq!(replayed, png);
+    assert_eq!(replayed.status, Some(200));
+    assert_eq!(replayed.headers["content-type"], "image/png");
+    assert!(cache.replay("https://example.com/1.png").is_err());
+    std::fs::remove_dir_all(cache.dir).ok();
+}
@@ -0,0 +1,384 @@
+use crate::{
+    cache::Mode,
+    limit::Limits,
+    notify::Notify,
+    schedule::Parallelism,
+    watch::Intervals,
+    CACHE,
+};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::{
+    collections::BTreeMap,
+    env,
+    fs::File,
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// Domain whose settings go for every domain without its own
+pub const ANY: &str = "*";
+
+impl Default for Config {
+    fn default() -> Self {
+        let mut sites = BTreeMap::new();
+        sites.insert(ANY.to_string(), Site {
+            headers: referer("https://manganato.com/"),
+            next:    Some("Next".to_string()),
+            limits:  None,
+        });
+        sites.insert("readmanganato.com".to_string(), Site {
+            headers: referer("https://readmanganato.com/"),
+            ..Default::default()
+        });
+        Self {
+            cache: PathBuf::from(CACHE),
+            library: None,
+            user_agent: None,
+            limits: Limits::default(),
+            parallelism: Parallelism::default(),
+            workers: 4,
+            sites,
+            visual: ["manga", "hentai", "pururin", "luscious"]
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+            text: ["novel", "royalroad", "comrademao"]
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+            intervals: Intervals::default(),
+            notify: Notify::default(),
+            mode: Mode::default(),
+            location: file(),
+        }
+    }
+}
+
+/// Settings read once at startup and handed to whatever needs them.
+/// Defaults are overridden by the config file, then the environment, then
+/// the command line.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Downloads, cached pages and everything kept between runs
+    pub cache:       PathBuf,
+    /// `library.json` in the cache when unset
+    pub library:     Option<PathBuf>,
+    /// Sent with every request instead of reqwest's
+    pub user_agent:  Option<String>,
+    /// Rate limits of the domains without their own
+    pub limits:      Limits,
+    pub parallelism: Parallelism,
+    /// Queued jobs run at once
+    pub workers:     usize,
+    /// Settings by domain, `ANY` for the rest
+    pub sites:       BTreeMap<String, Site>,
+    /// Parts of the origins of sites with images
+    pub visual:      Vec<String>,
+    /// Parts of the origins of sites with text
+    pub text:        Vec<String>,
+    /// How often books are checked for new chapters
+    pub intervals:   Intervals,
+    /// Where word of new chapters goes, books can have their own
+    pub notify:      Notify,
+    /// Whether pages and images come from the network, the page cache or
+    /// both
+    pub mode:        Mode,
+    /// The config file, if there's one to read
+    #[serde(skip)]
+    pub location:    Option<PathBuf>,
+}
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Site {
+    /// Sent along with requests for images
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Text of the link to the next chapter
+    #[serde(default)]
+    pub next:    Option<String>,
+    #[serde(default)]
+    pub limits:  Option<Limits>,
+}
+
+/// Settings given by the environment or on the command line, they win over
+/// the config file
+#[derive(Default, Debug, Clone)]
+pub struct Overrides {
+    pub file:        Option<PathBuf>,
+    pub cache:       Option<PathBuf>,
+    pub library:     Option<PathBuf>,
+    pub user_agent:  Option<String>,
+    /// Shortest time between two requests to a domain
+    pub delay:       Option<Duration>,
+    /// Downloads running at once
+    pub concurrency: Option<usize>,
+}
+
+/// `ehound/config.json` in the XDG config directory
+pub fn file() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| Some(PathBuf::from(env::var_os("HOME")?).join(".config")))
+        .map(|dir| dir.join("ehound").join("config.json"))
+}
+
+impl Config {
+    /// The defaults with the config file, the environment and then
+    /// `overrides` on top
+    pub fn load(overrides: Overrides) -> io::Result<Self> {
+        let overrides = overrides.or(Overrides::from_env()?);
+        let location = overrides.file.clone().or_else(file);
+        let mut value = serde_json::to_value(Self::default())?;
+        if let Some(location) = &location {
+            merge(&mut value, read(location)?);
+        }
+        let mut config: Self = serde_json::from_value(value)?;
+        config.location = location;
+        config.apply(overrides);
+        config.check()?;
+        Ok(config)
+    }
+
+    pub fn apply(&mut self, overrides: Overrides) {
+        self.cache = overrides.cache.unwrap_or_else(|| self.cache.clone());
+        self.library = overrides.library.or_else(|| self.library.clone());
+        self.user_agent =
+            overrides.user_agent.or_else(|| self.user_agent.clone());
+        if let Some(delay) = overrides.delay {
+            self.limits.min_delay = delay;
+        }
+        if let Some(concurrency) = overrides.concurrency {
+            self.parallelism.global = concurrency;
+        }
+    }
+
+    pub fn library(&self) -> PathBuf {
+        self.library
+            .clone()
+            .unwrap_or_else(|| self.cache.join("library.json"))
+    }
+
+    /// The domain's own settings over those of `ANY`. A header set to an
+    /// empty value isn't sent at all.
+    pub fn site(&self, domain: &str) -> Site {
+        let any = self.sites.get(ANY).cloned().unwrap_or_default();
+        let mut site = match self.sites.get(domain) {
+            Some(own) => Site {
+                headers: any
+                    .headers
+                    .into_iter()
+                    .filter(|(name, _)| {
+                        !own.headers.keys().any(|n| n.eq_ignore_ascii_case(name))
+                    })
+                    .chain(own.headers.clone())
+                    .collect(),
+                next:    own.next.clone().or(any.next),
+                limits:  own.limits.or(any.limits),
+            },
+            None => any,
+        };
+        site.headers.retain(|_, value| !value.is_empty());
+        site
+    }
+
+    /// Fails on headers that can't be sent
+    fn check(&self) -> io::Result<()> {
+        self.sites
+            .values()
+            .try_for_each(|site| site.header_map().map(|_| ()))
+    }
+}
+
+impl Site {
+    pub fn header_map(&self) -> io::Result<HeaderMap> {
+        self.headers
+            .iter()
+            .map(|(name, value)| {
+                let invalid = |e: &dyn ToString| {
+                    let e = format!("Bad header {}: {}", name, e.to_string());
+                    io::Error::new(io::ErrorKind::InvalidData, e)
+                };
+                Ok((
+                    name.parse::<HeaderName>().map_err(|e| invalid(&e))?,
+                    value.parse::<HeaderValue>().map_err(|e| invalid(&e))?,
+                ))
+            })
+            .collect()
+    }
+}
+
+impl Overrides {
+    /// `EHOUND_CONFIG`, `EHOUND_CACHE`, `EHOUND_LIBRARY`,
+    /// `EHOUND_USER_AGENT`, `EHOUND_DELAY` in seconds and
+    /// `EHOUND_CONCURRENCY`
+    pub fn from_env() -> io::Result<Self> {
+        let var = |name: &str| env::var(name).ok().filter(|v| !v.is_empty());
+        let invalid = |name: &str, value: &str| {
+            let e = format!("{} is not a valid {}", value, name);
+            io::Error::new(io::ErrorKind::InvalidInput, e)
+        };
+        Ok(Self {
+            file:        var("EHOUND_CONFIG").map(PathBuf::from),
+            cache:       var("EHOUND_CACHE").map(PathBuf::from),
+            library:     var("EHOUND_LIBRARY").map(PathBuf::from),
+            user_agent:  var("EHOUND_USER_AGENT"),
+            delay:       var("EHOUND_DELAY")
+                .map(|v| {
+                    v.parse::<f64>()
+                        .ok()
+                        .filter(|s| s.is_finite() && *s >= 0.)
+                        .map(Duration::from_secs_f64)
+                        .ok_or_else(|| invalid("EHOUND_DELAY", &v))
+                })
+                .transpose()?,
+            concurrency: var("EHOUND_CONCURRENCY")
+                .map(|v| v.parse().map_err(|_| invalid("EHOUND_CONCURRENCY", &v)))
+                .transpose()?,
+        })
+    }
+
+    /// These overrides, `other` where they're unset
+    pub fn or(self, other: Self) -> Self {
+        Self {
+            file:        self.file.or(other.file),
+            cache:       self.cache.or(other.cache),
+            library:     self.library.or(other.library),
+            user_agent:  self.user_agent.or(other.user_agent),
+            delay:       self.delay.or(other.delay),
+            concurrency: self.concurrency.or(other.concurrency),
+        }
+    }
+}
+
+/// Sets the dotted `key` of the config file to `value`, read as JSON or else
+/// as a string, or removes it. Domains in `sites.<domain>.<field>` keep
+/// their dots.
+pub fn edit(location: &Path, key: &str, value: Option<&str>) -> io::Result<()> {
+    let path = match key.split_once('.') {
+        Some(("sites", site)) => match site.rsplit_once('.') {
+            Some((domain, field)) => vec!["sites", domain, field],
+            None => vec!["sites", site],
+        },
+        _ => key.split('.').collect(),
+    };
+    let mut file = read(location)?;
+    let (last, parents) = path.split_last().expect("Split gives one at least");
+    let mut object = &mut file;
+    for key in parents {
+        if !object.is_object() {
+            *object = Value::Object(Map::new());
+        }
+        object = object
+            .as_object_mut()
+            .expect("Made an object")
+            .entry(key.to_string())
+            .or_insert_with(|| Value::Object(Map::new()));
+    }
+    let object = object.as_object_mut().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a table", key),
+        )
+    })?;
+    match value {
+        Some(value) => {
+            let value = serde_json::from_str(value)
+                .unwrap_or_else(|_| Value::String(value.to_string()));
+            object.insert(last.to_string(), value);
+        }
+        None => {
+            object.remove(*last);
+        }
+    }
+    // Only written if it still makes a valid config
+    let mut value = serde_json::to_value(Config::default())?;
+    merge(&mut value, file.clone());
+    serde_json::from_value::<Config>(value)?.check()?;
+    location.parent().map(std::fs::create_dir_all).transpose()?;
+    let tmp = location.with_extension("json.tmp");
+    serde_json::to_writer_pretty(File::create(&tmp)?, &file)?;
+    std::fs::rename(tmp, location)
+}
+
+/// The config file, empty if there's none
+fn read(location: &Path) -> io::Result<Value> {
+    match File::open(location) {
+        Ok(file) => Ok(serde_json::from_reader(BufReader::new(file))?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            Ok(Value::Object(Map::new()))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Puts `patch` over `base`, tables are merged key by key
+fn merge(base: &mut Value, patch: Value) {
+    match (base, patch) {
+        (Value::Object(base), Value::Object(patch)) => {
+            for (key, value) in patch {
+                match base.get_mut(&key) {
+                    Some(old) => merge(old, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, patch) => *base = patch,
+    }
+}
+
+fn referer(url: &str) -> BTreeMap<String, String> {
+    vec![("Referer".to_string(), url.to_string())]
+        .into_iter()
+        .collect()
+}
+
+#[test]
+fn config_layers() {
+    let dir = std::env::temp_dir()
+        .join(format!("ehound-config-{}", std::process::id()));
+    let location = dir.join("config.json");
+    edit(&location, "user_agent", Some("ehound")).unwrap();
+    edit(&location, "limits.rate", Some("0.5")).unwrap();
+    edit(&location, "sites.example.com.next", Some("Next Chapter")).unwrap();
+    let hour = r#"{"secs": 3600, "nanos": 0}"#;
+    edit(&location, "intervals.ongoing", Some(hour)).unwrap();
+    assert!(edit(&location, "workers", Some("many")).is_err());
+    assert!(edit(&location, "nothing", Some("1")).is_err());
+
+    let config = Config::load(Overrides {
+        file: Some(location.clone()),
+        delay: Some(Duration::from_secs(2)),
+        ..Default::default()
+    })
+    .unwrap();
+    assert_eq!(config.user_agent.as_deref(), Some("ehound"));
+    assert_eq!(config.limits.rate, 0.5);
+    assert_eq!(config.limits.burst, Limits::default().burst);
+    assert_eq!(config.limits.min_delay, Duration::from_secs(2));
+    assert_eq!(config.intervals.ongoing, Duration::from_secs(3600));
+    assert_eq!(config.intervals.hiatus, Intervals::default().hiatus);
+    let site = config.site("example.com");
+    assert_eq!(site.next.as_deref(), Some("Next Chapter"));
+    assert_eq!(site.headers["Referer"], "https://manganato.com/");
+    assert_eq!(config.site("other.com").next.as_deref(), Some("Next"));
+    edit(
+        &location,
+        "sites.example.com.headers",
+        Some(r#"{"referer": ""}"#),
+    )
+    .unwrap();
+    let config = Config::load(Overrides {
+        file: Some(location.clone()),
+        ..Default::default()
+    })
+    .unwrap();
+    assert!(config.site("example.com").headers.is_empty());
+    std::fs::remove_dir_all(dir).ok();
+}
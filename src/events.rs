@@ -0,0 +1,67 @@
+use crate::library::{BookId, BookName};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::sync::broadcast::{self, Receiver, Sender};
+
+impl Default for Events {
+    fn default() -> Self {
+        Self {
+            tx: broadcast::channel(Self::CAPACITY).0,
+        }
+    }
+}
+
+/// What's going on, as it happens
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Event {
+    BookDiscovered {
+        book: BookId,
+        name: BookName,
+    },
+    ChapterQueued {
+        book: BookId,
+        url:  String,
+        job:  u64,
+    },
+    PageDownloaded {
+        book:    BookId,
+        chapter: u16,
+        page:    u16,
+        bytes:   u64,
+    },
+    ChapterCompleted {
+        book:    BookId,
+        chapter: u16,
+        pages:   usize,
+        failed:  usize,
+    },
+    Error {
+        book:  Option<BookId>,
+        error: String,
+    },
+    /// A request to the domain had to wait its turn
+    RateLimited {
+        domain: String,
+        wait:   Duration,
+    },
+    /// An add, refresh or drain is over, `book` is set for a single book
+    Finished {
+        book: Option<BookId>,
+    },
+}
+
+/// Sends events to everyone subscribed, clones send to the same subscribers
+#[derive(Debug, Clone)]
+pub struct Events {
+    tx: Sender<Event>,
+}
+
+impl Events {
+    /// Subscribers falling further behind than this miss events
+    const CAPACITY: usize = 1024;
+
+    pub fn subscribe(&self) -> Receiver<Event> { self.tx.subscribe() }
+
+    /// Nobody listening is fine, the event is dropped
+    pub fn emit(&self, event: Event) { self.tx.send(event).ok(); }
+}
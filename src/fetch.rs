@@ -0,0 +1,314 @@
+use crate::retriever::DownloadError;
+use async_trait::async_trait;
+use futures::{
+    stream::{self, BoxStream},
+    StreamExt,
+};
+use once_cell::sync::Lazy;
+use reqwest::{
+    header::{HeaderMap, HeaderValue, CONTENT_TYPE, ETAG, IF_RANGE, RANGE},
+    Client,
+    StatusCode,
+    Url,
+};
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+/// One pooled client for everything that wasn't given its own fetcher
+static SHARED: Lazy<Arc<dyn Fetcher>> = Lazy::new(|| Arc::new(Http::default()));
+
+impl Default for Http {
+    fn default() -> Self {
+        Self {
+            client: Client::builder()
+                .cookie_store(true)
+                .build()
+                .expect("Couldn't build the HTTP client"),
+        }
+    }
+}
+
+/// Everything that goes over the network goes through here
+#[async_trait]
+pub trait Fetcher: fmt::Debug + Send + Sync {
+    async fn get(
+        &self, url: &str, headers: HeaderMap,
+    ) -> Result<Response, DownloadError>;
+}
+
+pub struct Response {
+    pub status:  u16,
+    pub headers: HeaderMap,
+    pub body:    BoxStream<'static, Result<Vec<u8>, DownloadError>>,
+}
+
+/// Fetcher backed by reqwest, clones share the connection pool and cookies
+#[derive(Debug, Clone)]
+pub struct Http {
+    client: Client,
+}
+
+/// In-memory fetcher answering with canned responses and remembering what
+/// was asked of it, unknown URLs get a 404
+#[derive(Default, Debug, Clone)]
+pub struct Mock {
+    responses: Arc<Mutex<HashMap<String, (u16, HeaderMap, Vec<u8>)>>>,
+    failures:  Arc<Mutex<HashMap<String, (u16, usize)>>>,
+    requests:  Arc<Mutex<Vec<String>>>,
+}
+
+pub fn shared() -> Arc<dyn Fetcher> { SHARED.clone() }
+
+impl Response {
+    pub fn content_type(&self) -> Option<String> {
+        self.headers
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string())
+    }
+
+    /// Turns 4xx and 5xx statuses into errors
+    pub fn error_for_status(self, url: &str) -> Result<Self, DownloadError> {
+        match self.status {
+            400..=599 => Err(DownloadError::Http {
+                url:    url.to_string(),
+                status: Some(self.status),
+                reason: StatusCode::from_u16(self.status)
+                    .map(|s| s.to_string())
+                    .unwrap_or_else(|_| self.status.to_string()),
+            }),
+            _ => Ok(self),
+        }
+    }
+
+    pub async fn bytes(self) -> Result<Vec<u8>, DownloadError> {
+        let mut body = self.body;
+        let mut data = vec![];
+        while let Some(chunk) = body.next().await {
+            data.extend(chunk?);
+        }
+        Ok(data)
+    }
+
+    pub async fn text(self) -> Result<String, DownloadError> {
+        Ok(String::from_utf8_lossy(&self.bytes().await?).into_owned())
+    }
+}
+
+impl Http {
+    pub fn new(client: Client) -> Self { Self { client } }
+}
+#[async_trait]
+impl Fetcher for Http {
+    async fn get(
+        &self, url: &str, headers: HeaderMap,
+    ) -> Result<Response, DownloadError> {
+        let owned = url.to_string();
+        let http = move |e: reqwest::Error| DownloadError::Http {
+            url:    owned.clone(),
+            status: e.status().map(|s| s.as_u16()),
+            reason: e.to_string(),
+        };
+        // A request that can't even be built won't do better on a retry
+        let invalid = |reason: String| DownloadError::InvalidUrl {
+            url: url.to_string(),
+            reason,
+        };
+        let req = self
+            .client
+            .get(url.parse::<Url>().map_err(|e| invalid(e.to_string()))?)
+            .headers(headers)
+            .build()
+            .map_err(|e| invalid(e.to_string()))?;
+        let resp = self.client.execute(req).await.map_err(|e| match e {
+            e if e.is_builder() => invalid(e.to_string()),
+            e => http(e),
+        })?;
+        Ok(Response {
+            status:  resp.status().as_u16(),
+            headers: resp.headers().clone(),
+            body:    resp
+                .bytes_stream()
+                .map(move |c| c.map(|b| b.to_vec()).map_err(&http))
+                .boxed(),
+        })
+    }
+}
+
+impl Mock {
+    pub fn page(&self, url: &str, html: &str) -> &Self {
+        self.respond(url, 200, Some("text/html"), html.as_bytes())
+    }
+
+    pub fn respond(
+        &self, url: &str, status: u16, mime: Option<&str>, body: &[u8],
+    ) -> &Self {
+        let mut headers = HeaderMap::new();
+        if let Some(mime) = mime.and_then(|m| HeaderValue::from_str(m).ok()) {
+            headers.insert(CONTENT_TYPE, mime);
+        }
+        self.responses
+            .lock()
+            .unwrap()
+            .insert(url.to_string(), (status, headers, body.to_vec()));
+        self
+    }
+
+    /// Sends `etag` along with the URL's response, ranges asked for with
+    /// another one in `If-Range` get the whole body
+    pub fn tag(&self, url: &str, etag: &str) -> &Self {
+        if let Some((_, headers, _)) = self.responses.lock().unwrap().get_mut(url)
+        {
+            headers.insert(ETAG, HeaderValue::from_str(etag).unwrap());
+        }
+        self
+    }
+
+    /// Answers the next `times` requests for the URL with `status`
+    pub fn fail(&self, url: &str, status: u16, times: usize) -> &Self {
+        self.failures
+            .lock()
+            .unwrap()
+            .insert(url.to_string(), (status, times));
+        self
+    }
+
+    /// URLs requested so far, in order
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+#[async_trait]
+impl Fetcher for Mock {
+    async fn get(
+        &self, url: &str, headers: HeaderMap,
+    ) -> Result<Response, DownloadError> {
+        self.requests.lock().unwrap().push(url.to_string());
+        if let Some((status, times)) = self
+            .failures
+            .lock()
+            .unwrap()
+            .get_mut(url)
+            .filter(|f| f.1 > 0)
+        {
+            *times -= 1;
+            return Ok(Response {
+                status:  *status,
+                headers: HeaderMap::new(),
+                body:    stream::once(async { Ok(vec![]) }).boxed(),
+            });
+        }
+        let canned = self.responses.lock().unwrap().get(url).cloned();
+        let (mut status, headers_out, mut body) =
+            canned.unwrap_or((404, HeaderMap::new(), vec![]));
+        let unchanged = headers
+            .get(IF_RANGE)
+            .map_or(true, |tag| Some(tag) == headers_out.get(ETAG));
+        let offset = headers
+            .get(RANGE)
+            .filter(|_| unchanged)
+            .and_then(|r| r.to_str().ok())
+            .and_then(|r| r.strip_prefix("bytes="))
+            .and_then(|r| r.trim_end_matches('-').parse::<usize>().ok());
+        match offset {
+            Some(o) if status == 200 && o >= body.len() => {
+                status = 416;
+                body.clear();
+            }
+            Some(o) if status == 200 => {
+                status = 206;
+                body.drain(..o);
+            }
+            _ => {}
+        }
+        Ok(Response {
+            status,
+            headers: headers_out,
+            body: stream::once(async { Ok(body) }).boxed(),
+        })
+    }
+}
+
+#[tokio::test]
+async fn mock_chapter() {
+    use crate::{
+        config::Config,
+        library::BookId,
+        retriever::Retriever,
+        retry::RetryPolicy,
+    };
+
+    let png =
+        b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR\0\0\0\x10\0\0\0\x20\x08\x06\0\0\0";
+    let url = "https://mock.manga.test/book/chapter-7";
+    let mock = Mock::default();
+    mock.page(
+        url,
+        "<html><title>Book Chapter 7</title><div>\
+         <img src=\"https://mock.manga.test/0.png\">\
+         <img src=\"https://mock.manga.test/1.png\"></div></html>",
+    )
+    .respond("https://mock.manga.test/0.png", 200, Some("image/png"), png)
+    .tag("https://mock.manga.test/0.png", "\"v2\"");
+    let cache = std::env::temp_dir()
+        .join(format!("ehound-mock-chapter-{}", std::process::id()));
+    let mut dl = Retriever::new(Arc::new(Config {
+        cache: cache.clone(),
+        ..Default::default()
+    }))
+    .with_fetcher(Arc::new(mock.clone()));
+    dl.retry.insert("mock.manga.test".to_string(), RetryPolicy {
+        base: std::time::Duration::from_millis(1),
+        ..Default::default()
+    });
+    let src = dl.try_fetch(url.to_string()).await.unwrap();
+    assert_eq!(src.title().to_string(), "Book");
+    let id = BookId::new("Mock book", url);
+    let down = dl.chapter(&id, src, Some(true), None).await;
+    let ch = down.chapter;
+    assert!(ch.pages()[&0].is_ok());
+    assert!(!ch.pages()[&1].is_ok());
+    assert!(matches!(down.errors[&1], DownloadError::Http {
+        status: Some(404),
+        ..
+    }));
+    assert_eq!(mock.requests()[0], url);
+
+    // A file that isn't the one recorded is downloaded again
+    let page = &ch.pages()[&0];
+    assert!(page.path.starts_with(&cache));
+    let dir = page.path.parent().unwrap().to_path_buf();
+    std::fs::write(&page.path, &png[..12]).unwrap();
+    let requests = mock.requests().len();
+    let cnt = dl
+        .content(&page.url, 0, true, &dir, Some(page))
+        .await
+        .unwrap();
+    assert_eq!(cnt.sha256, page.sha256);
+    assert_eq!(mock.requests().len(), requests + 1);
+
+    // Parts are resumed only while the server has the same file
+    let part = dir.join("0009.part");
+    let tag = dir.join("0009.part.tag");
+    for (prefix, etag) in &[(&b"<html>"[..], "\"v1\""), (&png[..8], "\"v2\"")] {
+        std::fs::write(&part, prefix).unwrap();
+        std::fs::write(&tag, etag).unwrap();
+        let (data, _) = dl.image(&page.url, &part).await.unwrap();
+        assert_eq!(data, png.to_vec());
+        assert!(!tag.exists());
+    }
+
+    // A page served instead of the image is asked for again
+    let busy = "https://mock.manga.test/busy.png".to_string();
+    mock.respond(&busy, 200, Some("text/html"), b"<html>Busy</html>");
+    let requests = mock.requests().len();
+    assert!(matches!(
+        dl.image(&busy, &part).await,
+        Err(DownloadError::Exhausted { attempts: 4, .. })
+    ));
+    assert_eq!(mock.requests().len(), requests + 4);
+    std::fs::remove_dir_all(cache).ok();
+}
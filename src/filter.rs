@@ -0,0 +1,182 @@
+use crate::source::get_place;
+use once_cell::sync::Lazy;
+use regex::{Regex, RegexSet, RegexSetBuilder};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeSet, fmt, str::FromStr};
+
+static VOLUME: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?i)\bvol(?:ume)?[\s._-]*(\d+)").unwrap());
+
+/// Which chapters of a book are downloaded, kept with the book so updates
+/// pick the same ones
+#[derive(
+    Default, Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize,
+)]
+pub struct ChapterFilter {
+    #[serde(default)]
+    pub range:   Option<Range>,
+    /// Volumes to download, all of them when empty
+    #[serde(default)]
+    pub volumes: BTreeSet<u16>,
+    /// Patterns of chapter titles to skip, case doesn't matter
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+#[derive(
+    Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize,
+)]
+pub enum Range {
+    /// Both ends included
+    Between(u16, u16),
+    From(u16),
+    /// The chapters with the highest numbers, anything newer is let through
+    /// once the book is added
+    Latest(usize),
+}
+
+impl ChapterFilter {
+    pub fn is_empty(&self) -> bool { *self == Self::default() }
+
+    /// Fails on the first pattern that isn't a valid regex
+    pub fn exclude(mut self, pattern: &str) -> Result<Self, regex::Error> {
+        Regex::new(pattern)?;
+        self.exclude.push(pattern.to_string());
+        Ok(self)
+    }
+
+    /// Whether the chapter at `url` titled `title` is to be downloaded,
+    /// `Latest` lets everything through
+    pub fn allows(&self, url: &str, title: &str) -> bool {
+        self.allows_with(&self.patterns(), url, title)
+    }
+
+    /// Keeps the links, each with its title, of the chapters to download
+    pub fn apply(&self, links: Vec<(String, String)>) -> Vec<(String, String)> {
+        let patterns = self.patterns();
+        let mut links = links
+            .into_iter()
+            .filter(|(url, title)| self.allows_with(&patterns, url, title))
+            .collect::<Vec<_>>();
+        if let Some(Range::Latest(n)) = self.range {
+            let mut nums =
+                links.iter().map(|(url, _)| num(url)).collect::<Vec<_>>();
+            nums.sort_unstable();
+            nums.dedup();
+            let lowest = nums.get(nums.len().saturating_sub(n)).copied();
+            links.retain(|(url, _)| {
+                n > 0 && lowest.map_or(true, |l| num(url) >= l)
+            });
+        }
+        links
+    }
+
+    fn allows_with(&self, patterns: &RegexSet, url: &str, title: &str) -> bool {
+        let n = num(url);
+        let in_range = match self.range {
+            Some(Range::Between(a, b)) => (a..=b).contains(&n),
+            Some(Range::From(a)) => n >= a,
+            Some(Range::Latest(_)) | None => true,
+        };
+        let in_volumes = self.volumes.is_empty() ||
+            volume(url, title).map_or(false, |v| self.volumes.contains(&v));
+        in_range && in_volumes && !patterns.is_match(title)
+    }
+
+    /// Invalid patterns are left out
+    fn patterns(&self) -> RegexSet {
+        let valid = self.exclude.iter().filter(|p| Regex::new(p).is_ok());
+        RegexSetBuilder::new(valid)
+            .case_insensitive(true)
+            .build()
+            .unwrap_or_else(|_| RegexSet::empty())
+    }
+}
+
+/// Chapter number as used for the book's chapters, 0 if there's none
+fn num(url: &str) -> u16 {
+    get_place(&url.to_string()).map_or(0, |place| place.1)
+}
+
+/// Volume named in the title or else the url
+fn volume(url: &str, title: &str) -> Option<u16> {
+    [title, url]
+        .iter()
+        .find_map(|s| VOLUME.captures(s)?.get(1)?.as_str().parse().ok())
+}
+
+/// "10-20", "from 100", "100-", "latest 5" or a single chapter
+impl FromStr for Range {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().to_lowercase();
+        let number = |s: &str| {
+            s.trim()
+                .parse::<u16>()
+                .map_err(|e| format!("{:?} is not a chapter number: {}", s, e))
+        };
+        let words = s.split_whitespace().collect::<Vec<_>>();
+        match words.as_slice() {
+            ["latest" | "last", n] => n
+                .parse()
+                .map(Range::Latest)
+                .map_err(|e| format!("{:?} is not a count: {}", n, e)),
+            ["from", n] => number(n).map(Range::From),
+            _ => match s.split_once('-') {
+                Some((a, "")) => number(a).map(Range::From),
+                Some((a, b)) => Ok(Range::Between(number(a)?, number(b)?)),
+                None => number(&s).map(|n| Range::Between(n, n)),
+            },
+        }
+    }
+}
+impl fmt::Display for Range {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Range::Between(a, b) if a == b => write!(f, "{}", a),
+            Range::Between(a, b) => write!(f, "{}-{}", a, b),
+            Range::From(a) => write!(f, "from {}", a),
+            Range::Latest(n) => write!(f, "latest {}", n),
+        }
+    }
+}
+
+#[test]
+fn filter_chapters() {
+    assert_eq!("10-20".parse(), Ok(Range::Between(10, 20)));
+    assert_eq!("from 100".parse(), Ok(Range::From(100)));
+    assert_eq!("Latest 5".parse(), Ok(Range::Latest(5)));
+    assert!("ten".parse::<Range>().is_err());
+
+    let links = (1..=6)
+        .map(|n| {
+            let title = match n {
+                4 => "Hiatus Notice".to_string(),
+                _ => format!("Vol. {} Chapter {}", (n + 2) / 3, n),
+            };
+            (format!("https://mock.novel.test/chapter-{}", n), title)
+        })
+        .collect::<Vec<_>>();
+    let nums = |filter: &ChapterFilter| {
+        filter
+            .apply(links.clone())
+            .iter()
+            .map(|(url, _)| num(url))
+            .collect::<Vec<_>>()
+    };
+    let filter = ChapterFilter::default().exclude("hiatus|q&a").unwrap();
+    assert_eq!(nums(&filter), vec![1, 2, 3, 5, 6]);
+    let filter = ChapterFilter {
+        range: Some(Range::Latest(2)),
+        ..filter
+    };
+    assert_eq!(nums(&filter), vec![5, 6]);
+    let filter = ChapterFilter {
+        range: Some(Range::Between(2, 5)),
+        volumes: vec![1].into_iter().collect(),
+        ..filter
+    };
+    assert_eq!(nums(&filter), vec![2, 3]);
+    assert!(filter.allows("https://mock.novel.test/chapter-2", "Vol 1"));
+    assert!(!filter.allows("https://mock.novel.test/chapter-2", "Vol 2"));
+}
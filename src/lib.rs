@@ -3,19 +3,44 @@
 #![feature(destructuring_assignment)]
 #![feature(slice_pattern)]
 
+pub mod cache;
+pub mod config;
+pub mod events;
+pub mod fetch;
+pub mod filter;
 pub mod library;
+pub mod limit;
+pub mod media;
+pub mod migrate;
+pub mod notify;
+pub mod queue;
 pub mod retriever;
+pub mod retry;
+pub mod schedule;
 pub mod source;
+pub mod sqlite;
+pub mod storage;
+pub mod text;
 pub mod update;
+pub mod verify;
+pub mod watch;
 
 pub const CACHE: &str = "./.cache";
 pub const TEST: &str = "https://readmanganato.com/manga-la988983";
 
 #[tokio::test]
 async fn manager_refresh() {
-    use crate::update::Manager;
+    use crate::update::{AddMode, Manager};
 
     let mut manager = Manager::default();
-    manager.add_book(None, TEST.to_string().into()).await;
+    manager
+        .add_book(
+            None,
+            TEST.to_string().into(),
+            AddMode::Download,
+            Default::default(),
+        )
+        .await
+        .ok();
     println!("{}", manager.refresh().await);
 }
@@ -1,44 +1,91 @@
-use crate::{source::Source, CACHE};
+use crate::{
+    config::Config,
+    filter::ChapterFilter,
+    migrate,
+    retriever::DownloadError,
+    source::Source,
+    storage,
+    text::Text,
+};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::{
     collections::{btree_map::Entry, BTreeMap},
     fs::File,
     hash::{Hash, Hasher},
-    io::Write,
+    io::{self, BufReader, Write},
     ops::Deref,
     path::PathBuf,
+    str::FromStr,
     u8,
 };
 
+impl Default for ContentStatus {
+    fn default() -> Self { ContentStatus::Placeholder }
+}
+impl Default for BookStatus {
+    fn default() -> Self { BookStatus::Unknown }
+}
 impl Default for Library {
     fn default() -> Self {
         Self {
+            version:  Library::VERSION,
             books:    BTreeMap::new(),
-            location: PathBuf::from(CACHE).join("library"),
+            location: Config::default().library(),
+            storage:  storage::Handle::default(),
         }
     }
 }
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Library {
-    pub books:    BTreeMap<BookName, Book>,
-    pub location: PathBuf,
+    #[serde(default)]
+    pub version:        u32,
+    pub books:          BTreeMap<BookId, Book>,
+    #[serde(skip)]
+    pub location:       PathBuf,
+    #[serde(skip)]
+    pub(crate) storage: storage::Handle,
 }
 #[derive(Default, Ord, PartialOrd, Eq, Debug, Clone, Serialize, Deserialize)]
 pub struct Book {
-    pub name:  BookName,
-    pub index: Source,
-    chapters:  BTreeMap<u16, Chapter>,
-    visual:    Option<bool>,
-    pub pos:   u16,
+    pub id:              BookId,
+    pub name:            BookName,
+    /// Names the book was known by before being renamed
+    #[serde(default)]
+    pub aliases:         Vec<BookName>,
+    pub index:           Source,
+    pub(crate) chapters: BTreeMap<u16, Chapter>,
+    pub(crate) visual:   Option<bool>,
+    pub pos:             u16,
+    #[serde(default)]
+    pub status:          BookStatus,
+    #[serde(default)]
+    pub meta:            BTreeMap<String, String>,
+    /// When a chapter was last added
+    #[serde(default)]
+    pub updated:         Option<DateTime<Utc>>,
+    /// Chapters to download, both when adding and updating the book
+    #[serde(default)]
+    pub filter:          ChapterFilter,
+}
+#[derive(
+    Eq, PartialEq, Ord, PartialOrd, Debug, Clone, Copy, Serialize, Deserialize,
+)]
+pub enum BookStatus {
+    Unknown,
+    Ongoing,
+    Completed,
+    Hiatus,
 }
 //TODO: implement Default Chapter
 #[derive(
     Default, Eq, PartialEq, Ord, PartialOrd, Debug, Clone, Serialize, Deserialize,
 )]
 pub struct Chapter {
-    pub page: Source,
-    content:  BTreeMap<u16, Content>,
-    pub pos:  u16,
+    pub page:           Source,
+    pub(crate) content: BTreeMap<u16, Content>,
+    pub pos:            u16,
 }
 #[derive(
     Hash,
@@ -53,55 +100,182 @@ pub struct Chapter {
     Deserialize,
 )]
 pub struct BookName(String);
+/// Key of a book in the library and the name of its cache directory, doesn't
+/// change when the book is renamed
+#[derive(
+    Hash,
+    Eq,
+    PartialEq,
+    Ord,
+    PartialOrd,
+    Default,
+    Debug,
+    Clone,
+    Serialize,
+    Deserialize,
+)]
+pub struct BookId(String);
 //TODO: implement Default for Content
 #[derive(
     Default, Eq, PartialEq, Ord, PartialOrd, Debug, Clone, Serialize, Deserialize,
 )]
-pub struct Content(pub u16, pub PathBuf);
+pub struct Content {
+    pub num:        u16,
+    pub path:       PathBuf,
+    pub url:        String,
+    pub referer:    Option<String>,
+    pub sha256:     Option<String>,
+    pub mime:       Option<String>,
+    pub size:       u64,
+    pub dimensions: Option<(u32, u32)>,
+    pub words:      Option<usize>,
+    pub fetched:    Option<DateTime<Utc>>,
+    pub status:     ContentStatus,
+}
+#[derive(
+    Eq, PartialEq, Ord, PartialOrd, Debug, Clone, Serialize, Deserialize,
+)]
+pub enum ContentStatus {
+    Ok,
+    Failed(String),
+    Missing,
+    Placeholder,
+}
 
 impl Library {
-    pub async fn get(&self, book: &BookName) -> Option<&Book> {
+    /// Schema version written into saved libraries
+    pub const VERSION: u32 = migrate::LIBRARY.current();
+
+    /// Writes the library to the storage backend picked by its location
+    pub async fn save(&self) -> io::Result<()> {
+        self.storage.with(&self.location, |s| s.save(self))
+    }
+
+    /// Writes a single book, leaving the rest of the stored library as is
+    pub async fn save_book(&self, book: &BookId) -> io::Result<()> {
+        self.storage
+            .with(&self.location, |s| match self.books.get(book) {
+                Some(book) => s.save_book(book),
+                None => s.remove_book(book),
+            })
+    }
+
+    /// The library at `location`, which stays open for later saves
+    pub async fn load(location: PathBuf) -> io::Result<Self> {
+        let storage = storage::Handle::default();
+        let mut lib = storage.with(&location, |s| s.load())?;
+        lib.storage = storage;
+        Ok(lib)
+    }
+
+    pub async fn get(&self, book: &BookId) -> Option<&Book> {
         self.books.get(book)
     }
 
-    pub fn rename_book(&mut self, old: &BookName, new_name: BookName) {
-        if self.books.contains_key(old) {
-            let mut book = self.books.remove(old).unwrap();
-            book.name = new_name.clone();
-            self.books.insert(new_name, book);
+    /// Looks a book up by its id, name or one of its former names
+    pub fn find(&self, name: &str) -> Option<&Book> {
+        let name = name.to_lowercase();
+        self.books.get(&BookId(name.clone())).or_else(|| {
+            self.books.values().find(|b| {
+                std::iter::once(&b.name)
+                    .chain(&b.aliases)
+                    .any(|n| n.to_lowercase() == name)
+            })
+        })
+    }
+
+    /// Adds the book under a free id, keeping an existing book with the same
+    /// id and index
+    pub fn insert(&mut self, mut book: Book) -> &mut Book {
+        let base = book.id.clone();
+        let mut n = 1;
+        while let Some(b) = self.books.get(&book.id) {
+            if b.index.location == book.index.location {
+                break;
+            }
+            n += 1;
+            book.id = BookId(format!("{}-{}", *base, n));
+        }
+        self.books.entry(book.id.clone()).or_insert(book)
+    }
+
+    pub fn rename_book(&mut self, id: &BookId, new_name: BookName) {
+        if let Some(book) = self.books.get_mut(id) {
+            let old = std::mem::replace(&mut book.name, new_name);
+            let name = &book.name;
+            book.aliases.retain(|a| a != name);
+            if !book.aliases.contains(&old) {
+                book.aliases.push(old);
+            }
         }
     }
 
     pub async fn add_book(
         &mut self, book: BookName, site: Option<Source>,
-    ) -> &mut Book {
-        if let Some(src) = site {
-            let b = Book {
-                name: book.clone(),
-                index: src.refresh().await.index().await,
-                ..Default::default()
-            };
-            self.books.entry(book).or_insert(b)
-        } else {
-            self.books.entry(book).or_default()
+    ) -> Result<&mut Book, DownloadError> {
+        match site {
+            Some(src) => {
+                let index = src.try_refresh().await?.index().await;
+                Ok(self.insert(Book::new(book, index)))
+            }
+            None => Ok(self.insert(Book::from(book))),
         }
     }
 
-    pub async fn remove_book(&mut self, book: BookName) {
+    pub async fn remove_book(&mut self, book: BookId) {
         self.books.remove(&book);
     }
 
-    pub async fn set_source(&mut self, book: BookName, url: Option<String>) {
+    pub async fn set_source(
+        &mut self, book: BookId, url: Option<String>,
+    ) -> Result<(), DownloadError> {
         match (self.books.entry(book), url) {
             (Entry::Occupied(mut e), Some(url)) => {
-                e.get_mut().index = Source::from(url).refresh().await;
+                e.get_mut().index = Source::from(url).try_refresh().await?;
+            }
+            (Entry::Occupied(mut e), None) => {
+                let (id, name) = (e.get().id.clone(), e.get().name.clone());
+                *e.get_mut() = Book {
+                    id,
+                    name,
+                    ..Default::default()
+                }
             }
-            (Entry::Occupied(mut e), None) => *e.get_mut() = Default::default(),
             _ => {}
         }
+        Ok(())
+    }
+}
+impl BookId {
+    /// Slug of the name followed by a short hash of where the book is from,
+    /// so the same title on two sites gets two ids
+    pub fn new(name: &str, origin: &str) -> Self {
+        let slug = name
+            .to_lowercase()
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|w| !w.is_empty())
+            .collect::<Vec<_>>()
+            .join("-")
+            .chars()
+            .take(48)
+            .collect::<String>();
+        let slug = match slug.trim_end_matches('-') {
+            "" => "book",
+            s => s,
+        };
+        Self(format!("{}-{}", slug, &digest(origin.as_bytes())[..8]))
     }
 }
 impl Book {
+    pub fn new(name: BookName, index: Source) -> Self {
+        Self {
+            id: BookId::new(&name, &index.location),
+            name,
+            index,
+            ..Default::default()
+        }
+    }
+
     pub fn set_visual(&mut self, visual: Option<bool>) {
         match visual {
             Some(_) => self.visual = visual,
@@ -112,6 +286,7 @@ impl Book {
     pub fn visual(&self) -> Option<bool> { self.visual }
 
     pub async fn add_chapter(&mut self, ch: Chapter) -> Option<Chapter> {
+        self.updated = Some(Utc::now());
         self.chapters.insert(ch.num(), ch)
     }
 
@@ -121,6 +296,12 @@ impl Book {
 
     pub fn get(&mut self, ch: u16) -> Option<&Chapter> { self.chapters.get(&ch) }
 
+    pub fn chapters(&self) -> &BTreeMap<u16, Chapter> { &self.chapters }
+
+    pub fn chapter_mut(&mut self, ch: u16) -> Option<&mut Chapter> {
+        self.chapters.get_mut(&ch)
+    }
+
     pub fn seek(&mut self, chapter: u16) -> Option<Chapter> {
         let e = self.chapters.get(&chapter).cloned();
         e.is_some().then(|| self.pos = chapter);
@@ -143,15 +324,25 @@ impl Book {
 }
 impl Chapter {
     pub fn add_content(&mut self, content: Content) -> Option<Content> {
-        self.content.insert(content.0, content)
+        self.content.insert(content.num, content)
     }
 
     pub fn remove_content(&mut self, content: Content) -> Option<Content> {
-        self.content.remove(&content.0)
+        self.content.remove(&content.num)
     }
 
     pub fn get(&mut self, p: u16) -> Option<&Content> { self.content.get(&p) }
 
+    pub fn pages(&self) -> &BTreeMap<u16, Content> { &self.content }
+
+    /// Page numbers missing from the sequence up to the last known page
+    pub fn gaps(&self) -> Vec<u16> {
+        let last = self.content.keys().last().copied().unwrap_or_default();
+        (0..last)
+            .filter(|p| !self.content.contains_key(p))
+            .collect()
+    }
+
     pub fn num(&self) -> u16 { self.page.place.1 }
 
     pub fn seek(&mut self, page: u16) -> Option<Content> {
@@ -174,26 +365,78 @@ impl Chapter {
         }
     }
 }
+impl BookStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BookStatus::Unknown => "unknown",
+            BookStatus::Ongoing => "ongoing",
+            BookStatus::Completed => "completed",
+            BookStatus::Hiatus => "hiatus",
+        }
+    }
+}
+impl FromStr for BookStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "unknown" => Ok(BookStatus::Unknown),
+            "ongoing" => Ok(BookStatus::Ongoing),
+            "completed" => Ok(BookStatus::Completed),
+            "hiatus" => Ok(BookStatus::Hiatus),
+            _ => Err(format!("Unknown book status {}", s)),
+        }
+    }
+}
 impl Content {
-    pub fn save(&self, data: &[u8]) {
-        let pb = &self.1;
-        std::fs::create_dir_all(pb).unwrap();
-        let pb = &pb.join(format!("{:04}.jpg", self.0));
+    pub fn new(num: u16, url: String) -> Self {
+        Self {
+            num,
+            url,
+            ..Default::default()
+        }
+    }
+
+    /// Records the size, hash and fetch time of data already on disk
+    pub fn record(&mut self, data: &[u8]) {
+        self.size = data.len() as u64;
+        self.sha256 = Some(digest(data));
+        self.fetched = Some(Utc::now());
+        self.status = ContentStatus::Ok;
+    }
+
+    pub fn save(&mut self, data: &[u8]) -> io::Result<()> {
+        self.record(data);
+        let pb = &self.path;
+        pb.parent().map(std::fs::create_dir_all).transpose()?;
         File::with_options()
             .write(true)
             .create(true)
-            .open(pb)
-            .unwrap()
-            .write(data)
-            .unwrap();
+            .truncate(true)
+            .open(pb)?
+            .write_all(data)
+    }
+
+    pub fn save_text(&mut self, text: &Text) -> io::Result<()> {
+        self.words = Some(text.meta.words);
+        self.save(serde_json::to_string_pretty(text)?.as_bytes())
+    }
+
+    pub fn load_text(&self) -> Option<Text> {
+        serde_json::from_reader(BufReader::new(File::open(&self.path).ok()?)).ok()
+    }
+
+    pub fn fail(&mut self, reason: String) {
+        self.status = ContentStatus::Failed(reason);
     }
 
+    pub fn is_ok(&self) -> bool { self.status == ContentStatus::Ok }
+
     pub fn file(&self) -> File {
-        let pb = &self.1;
-        std::fs::create_dir_all(pb).unwrap();
-        let pb = &pb.join(format!("{}", self.0));
-        std::fs::create_dir_all(pb).unwrap();
+        let pb = &self.path;
+        pb.parent().map(|dir| std::fs::create_dir_all(dir).unwrap());
         File::with_options()
+            .read(true)
             .write(true)
             .create(true)
             .open(pb)
@@ -201,28 +444,21 @@ impl Content {
     }
 }
 
+/// Hex encoded SHA-256 of the data
+pub fn digest(data: &[u8]) -> String { format!("{:x}", Sha256::digest(data)) }
+
 impl PartialEq for Book {
-    fn eq(&self, other: &Self) -> bool { self.name == other.name }
+    fn eq(&self, other: &Self) -> bool { self.id == other.id }
 }
 impl Hash for Book {
-    fn hash<H: Hasher>(&self, state: &mut H) { self.name.hash(state); }
+    fn hash<H: Hasher>(&self, state: &mut H) { self.id.hash(state); }
 }
 
 impl From<String> for Book {
-    fn from(name: String) -> Self {
-        Self {
-            name: name.into(),
-            ..Default::default()
-        }
-    }
+    fn from(name: String) -> Self { Self::from(BookName::from(name)) }
 }
 impl From<BookName> for Book {
-    fn from(name: BookName) -> Self {
-        Self {
-            name: name.into(),
-            ..Default::default()
-        }
-    }
+    fn from(name: BookName) -> Self { Self::new(name, Source::default()) }
 }
 impl From<String> for BookName {
     fn from(name: String) -> Self { Self(name) }
@@ -232,8 +468,62 @@ impl Deref for Book {
 
     fn deref<'a>(&'a self) -> &'a BookName { &self.name }
 }
+impl From<String> for BookId {
+    fn from(id: String) -> Self { Self(id) }
+}
+impl Deref for BookId {
+    type Target = String;
+
+    fn deref<'a>(&'a self) -> &'a String { &self.0 }
+}
+impl std::fmt::Display for BookId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
 impl Deref for BookName {
     type Target = String;
 
     fn deref<'a>(&'a self) -> &'a String { &self.0 }
 }
+
+#[tokio::test]
+async fn library_persist() {
+    let dir = std::env::temp_dir()
+        .join(format!("ehound-persist-{}", std::process::id()));
+    let mut lib = Library {
+        location: dir.join("library.json"),
+        ..Default::default()
+    };
+    lib.insert(Book::from("One".to_string()));
+    lib.save().await.unwrap();
+    lib.insert(Book::from("Two".to_string()));
+    lib.save().await.unwrap();
+
+    let loaded = Library::load(lib.location.clone()).await.unwrap();
+    assert_eq!(loaded.books.len(), 2);
+    assert_eq!(loaded.location, lib.location);
+    std::fs::write(&lib.location, "{ corrupted").unwrap();
+    let backup = Library::load(lib.location.clone()).await.unwrap();
+    assert_eq!(backup.books.len(), 1);
+    backup.save().await.unwrap();
+    std::fs::remove_dir_all(dir).ok();
+}
+
+#[test]
+fn library_ids() {
+    let mut lib = Library::default();
+    let a = Book::new("Solo: Leveling!".to_string().into(), Source::default());
+    let b = Book {
+        index: Source::from("https://other.site/solo-leveling".to_string()),
+        ..a.clone()
+    };
+    assert!(a.id.starts_with("solo-leveling-"));
+    let a = lib.insert(a).id.clone();
+    let b = lib.insert(b).id.clone();
+    assert_ne!(a, b);
+
+    lib.rename_book(&a, "Only I Level Up".to_string().into());
+    assert_eq!(lib.find("solo: leveling!").map(|b| &b.id), Some(&a));
+    assert_eq!(lib.find("Only I Level Up").map(|b| &b.id), Some(&a));
+}
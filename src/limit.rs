@@ -0,0 +1,216 @@
+use crate::{
+    config::ANY,
+    events::{Event, Events},
+    fetch::{Fetcher, Response},
+    retriever::DownloadError,
+};
+use async_trait::async_trait;
+use futures::StreamExt;
+use reqwest::{header::HeaderMap, Url};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::Instant,
+};
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            rate:        2.,
+            burst:       4,
+            min_delay:   Duration::from_millis(100),
+            connections: 4,
+        }
+    }
+}
+
+/// How hard a single domain may be hit
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Limits {
+    /// Requests per second in the long run
+    pub rate:        f64,
+    /// Requests that may go out at once after a quiet period
+    pub burst:       u32,
+    /// Shortest time between two requests, bursts included
+    pub min_delay:   Duration,
+    /// Requests in flight at the same time
+    pub connections: usize,
+}
+
+/// Token bucket per domain. Domains are looked up under a short lived lock
+/// and all the waiting happens outside of it, so a slow site never holds up
+/// requests to the others.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    limits:  BTreeMap<String, Limits>,
+    buckets: Mutex<HashMap<String, Arc<Bucket>>>,
+}
+
+/// Fetcher waiting for its turn with the limiter before every request
+#[derive(Debug, Clone)]
+pub struct Limited {
+    pub inner:   Arc<dyn Fetcher>,
+    pub limiter: Arc<RateLimiter>,
+    pub events:  Events,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    limits:      Limits,
+    state:       Mutex<State>,
+    connections: Arc<Semaphore>,
+}
+#[derive(Debug)]
+struct State {
+    tokens: f64,
+    last:   Instant,
+    /// Earliest time the next request may go out
+    next:   Instant,
+}
+
+/// A request slot, the connection is given back when it's dropped
+#[derive(Debug)]
+pub struct Permit {
+    /// How long it took to get the permit
+    pub waited:  Duration,
+    _connection: OwnedSemaphorePermit,
+}
+
+impl RateLimiter {
+    pub fn new(limits: BTreeMap<String, Limits>) -> Self {
+        Self {
+            limits,
+            ..Default::default()
+        }
+    }
+
+    /// The domain's own limits, else those of `ANY`, else the defaults
+    pub fn limits(&self, domain: &str) -> Limits {
+        self.limits
+            .get(domain)
+            .or_else(|| self.limits.get(ANY))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Waits until a request to `domain` is allowed
+    pub async fn acquire(&self, domain: &str) -> Permit {
+        let start = Instant::now();
+        let bucket = self
+            .buckets
+            .lock()
+            .unwrap()
+            .entry(domain.to_string())
+            .or_insert_with(|| Arc::new(Bucket::new(self.limits(domain))))
+            .clone();
+        let connection = bucket
+            .connections
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("The semaphore is never closed");
+        tokio::time::sleep_until(bucket.reserve()).await;
+        Permit {
+            waited:      start.elapsed(),
+            _connection: connection,
+        }
+    }
+}
+
+impl Bucket {
+    fn new(limits: Limits) -> Self {
+        let now = Instant::now();
+        Self {
+            limits,
+            state: Mutex::new(State {
+                tokens: limits.burst.max(1) as f64,
+                last:   now,
+                next:   now,
+            }),
+            connections: Arc::new(Semaphore::new(limits.connections.max(1))),
+        }
+    }
+
+    /// Takes a token, possibly one that's only refilled in the future, and
+    /// returns when it may be used
+    fn reserve(&self) -> Instant {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let rate = self.limits.rate.max(f64::EPSILON);
+        let refill = now.saturating_duration_since(state.last).as_secs_f64();
+        state.tokens =
+            (state.tokens + refill * rate).min(self.limits.burst.max(1) as f64);
+        state.last = now;
+        state.tokens -= 1.;
+        let refilled = match state.tokens < 0. {
+            true => now + Duration::from_secs_f64(-state.tokens / rate),
+            false => now,
+        };
+        let at = refilled.max(state.next);
+        state.next = at + self.limits.min_delay;
+        at
+    }
+}
+
+#[async_trait]
+impl Fetcher for Limited {
+    async fn get(
+        &self, url: &str, headers: HeaderMap,
+    ) -> Result<Response, DownloadError> {
+        let domain = url
+            .parse::<Url>()
+            .ok()
+            .and_then(|u| u.domain().map(|d| d.to_string()))
+            .unwrap_or_default();
+        let permit = self.limiter.acquire(&domain).await;
+        if permit.waited > Duration::from_millis(1) {
+            self.events.emit(Event::RateLimited {
+                domain,
+                wait: permit.waited,
+            });
+        }
+        let resp = self.inner.get(url, headers).await?;
+        // The connection stays taken until the body has been read
+        Ok(Response {
+            body: resp
+                .body
+                .map(move |chunk| {
+                    let _ = &permit;
+                    chunk
+                })
+                .boxed(),
+            ..resp
+        })
+    }
+}
+
+#[tokio::test]
+async fn limit_domains() {
+    let limits = Limits {
+        rate:        10.,
+        burst:       2,
+        min_delay:   Duration::from_millis(0),
+        connections: 1,
+    };
+    let limiter = Arc::new(RateLimiter::new(
+        vec![("slow.test".to_string(), limits)]
+            .into_iter()
+            .collect(),
+    ));
+    let start = Instant::now();
+    for _ in 0..4 {
+        limiter.acquire("slow.test").await;
+    }
+    // Two from the burst, then one every 100ms
+    assert!(start.elapsed() >= Duration::from_millis(190));
+    let held = limiter.acquire("slow.test").await;
+    let other = Instant::now();
+    limiter.acquire("other.test").await;
+    assert!(other.elapsed() < Duration::from_millis(50));
+    drop(held);
+}
@@ -0,0 +1,94 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(
+    Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize,
+)]
+pub enum Format {
+    Jpeg,
+    Png,
+    Gif,
+    WebP,
+    Avif,
+    Bmp,
+}
+
+impl Format {
+    pub const ALL: [Format; 6] = [
+        Format::Jpeg,
+        Format::Png,
+        Format::Gif,
+        Format::WebP,
+        Format::Avif,
+        Format::Bmp,
+    ];
+
+    /// Recognizes an image by its magic bytes
+    pub fn sniff(data: &[u8]) -> Option<Self> {
+        match data {
+            [0xFF, 0xD8, 0xFF, ..] => Some(Format::Jpeg),
+            [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, ..] => {
+                Some(Format::Png)
+            }
+            [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => Some(Format::Gif),
+            [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => {
+                Some(Format::WebP)
+            }
+            [_, _, _, _, b'f', b't', b'y', b'p', b'a', b'v', b'i', b'f' | b's', ..] => {
+                Some(Format::Avif)
+            }
+            [b'B', b'M', ..] => Some(Format::Bmp),
+            _ => None,
+        }
+    }
+
+    pub fn from_mime(mime: &str) -> Option<Self> {
+        match mime.split(';').next()?.trim() {
+            "image/jpeg" | "image/jpg" => Some(Format::Jpeg),
+            "image/png" => Some(Format::Png),
+            "image/gif" => Some(Format::Gif),
+            "image/webp" => Some(Format::WebP),
+            "image/avif" => Some(Format::Avif),
+            "image/bmp" => Some(Format::Bmp),
+            _ => None,
+        }
+    }
+
+    pub fn mime(&self) -> &'static str {
+        match self {
+            Format::Jpeg => "image/jpeg",
+            Format::Png => "image/png",
+            Format::Gif => "image/gif",
+            Format::WebP => "image/webp",
+            Format::Avif => "image/avif",
+            Format::Bmp => "image/bmp",
+        }
+    }
+
+    pub fn ext(&self) -> &'static str {
+        match self {
+            Format::Jpeg => "jpg",
+            Format::Png => "png",
+            Format::Gif => "gif",
+            Format::WebP => "webp",
+            Format::Avif => "avif",
+            Format::Bmp => "bmp",
+        }
+    }
+}
+
+/// Width and height in pixels read from the image header
+pub fn dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    imagesize::blob_size(data)
+        .ok()
+        .map(|s| (s.width as u32, s.height as u32))
+}
+
+#[test]
+fn media_sniff() {
+    let png =
+        b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR\0\0\0\x10\0\0\0\x20\x08\x06\0\0\0";
+    assert_eq!(Format::sniff(png), Some(Format::Png));
+    assert_eq!(dimensions(png), Some((16, 32)));
+    assert_eq!(Format::sniff(b"<!DOCTYPE html><html>"), None);
+    assert_eq!(Format::from_mime("image/webp; q=1"), Some(Format::WebP));
+}
@@ -0,0 +1,304 @@
+use crate::library::BookId;
+use serde_json::{json, Map, Value};
+use std::{
+    fs::File,
+    io,
+    io::BufReader,
+    path::{Path, PathBuf},
+};
+
+/// Upgrades a document by one version, describing what it changed
+pub type Step = fn(&mut Value, &mut Migration);
+
+/// An on-disk format and the chain of steps leading to its current version,
+/// `steps[n]` turning version `n` into `n + 1`
+pub struct Schema {
+    pub name:  &'static str,
+    pub steps: &'static [Step],
+}
+#[derive(Default, Debug, Clone, Eq, PartialEq)]
+pub struct Migration {
+    pub from:    u32,
+    pub to:      u32,
+    pub changes: Vec<String>,
+    /// Directories to rename so the files match the upgraded document
+    pub moves:   Vec<(PathBuf, PathBuf)>,
+}
+
+pub const LIBRARY: Schema = Schema {
+    name:  "library",
+    steps: &[library_v1, library_v2, library_v3],
+};
+pub const RETRIEVER: Schema = Schema {
+    name:  "retriever",
+    steps: &[retriever_v1],
+};
+
+impl Schema {
+    pub const fn current(&self) -> u32 { self.steps.len() as u32 }
+
+    /// Brings the document up to the current version in place
+    pub fn upgrade(&self, value: &mut Value) -> io::Result<Migration> {
+        let from = version(value);
+        let mut migration = Migration {
+            from,
+            to: self.current(),
+            ..Default::default()
+        };
+        if from > self.current() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} version {} is newer than supported ({})",
+                    self.name,
+                    from,
+                    self.current()
+                ),
+            ));
+        }
+        for step in &self.steps[from as usize..] {
+            step(value, &mut migration);
+        }
+        if let Some(obj) = value.as_object_mut() {
+            obj.insert("version".to_string(), self.current().into());
+        }
+        Ok(migration)
+    }
+
+    /// Upgrades the file at `path`, leaving the original next to it as
+    /// `<name>.v<old version>`, and moves the files it refers to. With
+    /// `dry_run` only reports what would change.
+    pub fn migrate_file(
+        &self, path: &Path, dry_run: bool,
+    ) -> io::Result<Migration> {
+        let mut value: Value =
+            serde_json::from_reader(BufReader::new(File::open(path)?))?;
+        let migration = self.upgrade(&mut value)?;
+        if !dry_run && migration.from != migration.to {
+            let mut backup = path.as_os_str().to_owned();
+            backup.push(format!(".v{}", migration.from));
+            std::fs::copy(path, &backup)?;
+            migration.move_files()?;
+            let tmp = path.with_extension("migrating");
+            serde_json::to_writer(File::create(&tmp)?, &value)?;
+            std::fs::rename(tmp, path)?;
+        }
+        Ok(migration)
+    }
+}
+
+impl Migration {
+    /// Renames the directories the upgrade moved, skipping those already
+    /// moved or never downloaded
+    pub fn move_files(&self) -> io::Result<()> {
+        for (from, to) in &self.moves {
+            if from.is_dir() && !to.exists() {
+                to.parent().map(std::fs::create_dir_all).transpose()?;
+                std::fs::rename(from, to)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Version recorded in the document, files from before versioning count as 0
+pub fn version(value: &Value) -> u32 {
+    value
+        .get("version")
+        .and_then(Value::as_u64)
+        .unwrap_or_default() as u32
+}
+
+/// `Content(num, dir)` tuples become records pointing at the page file, and
+/// the library stops storing its own location. The tuple's number could be
+/// the chapter's rather than the page's, so the file is looked up under both
+/// and pages without one are marked missing for `verify` to report.
+fn library_v1(value: &mut Value, migration: &mut Migration) {
+    let changes = &mut migration.changes;
+    let obj = match value.as_object_mut() {
+        Some(obj) => obj,
+        None => return,
+    };
+    obj.remove("location")
+        .map(|_| changes.push("dropped library location".to_string()));
+    let books = obj.get_mut("books").and_then(Value::as_object_mut);
+    for (name, book) in books.into_iter().flatten() {
+        let chapters = book.get_mut("chapters").and_then(Value::as_object_mut);
+        for (num, ch) in chapters.into_iter().flatten() {
+            let pages = ch.get_mut("content").and_then(Value::as_object_mut);
+            for (page, content) in pages.into_iter().flatten() {
+                if let Some([n, dir]) = content.as_array().map(Vec::as_slice) {
+                    let dir = Path::new(dir.as_str().unwrap_or_default());
+                    let at = page.parse().ok().or_else(|| n.as_u64());
+                    let at = at.unwrap_or_default();
+                    let file = |n: u64| dir.join(format!("{:04}.jpg", n));
+                    let found = std::iter::once(at)
+                        .chain(n.as_u64())
+                        .map(file)
+                        .find(|path| path.is_file());
+                    let path = found.clone().unwrap_or_else(|| file(at));
+                    let found = found.is_some();
+                    *content = json!({
+                        "num": at,
+                        "path": path,
+                        "url": "",
+                        "referer": null,
+                        "sha256": null,
+                        "mime": null,
+                        "size": 0,
+                        "dimensions": null,
+                        "words": null,
+                        "fetched": null,
+                        "status": if found { "Ok" } else { "Missing" },
+                    });
+                    changes.push(format!(
+                        "{} chapter {} page {}: content tuple to record{}",
+                        name,
+                        num,
+                        page,
+                        if found { "" } else { ", file missing" }
+                    ));
+                }
+            }
+        }
+    }
+}
+
+/// Books are keyed by a stable id instead of their name, which also names
+/// their directory in the cache
+fn library_v2(value: &mut Value, migration: &mut Migration) {
+    let books = match value.get_mut("books").and_then(Value::as_object_mut) {
+        Some(books) => books,
+        None => return,
+    };
+    *books = std::mem::take(books)
+        .into_iter()
+        .map(|(name, mut book)| {
+            let origin = book["index"]["location"].as_str().unwrap_or_default();
+            let id = BookId::new(&name, origin).to_string();
+            migration
+                .changes
+                .push(format!("{} keyed by id {}", name, id));
+            book["id"] = id.clone().into();
+            book["aliases"] = json!([]);
+            let chapters =
+                book.get_mut("chapters").and_then(Value::as_object_mut);
+            let pages = chapters
+                .into_iter()
+                .flat_map(|chs| chs.values_mut())
+                .filter_map(|ch| ch.get_mut("content"))
+                .filter_map(Value::as_object_mut)
+                .flat_map(|pages| pages.values_mut());
+            for content in pages {
+                let path = match content["path"].as_str() {
+                    Some(path) => PathBuf::from(path),
+                    None => continue,
+                };
+                let (from, to) = match rekey(&path, &name, &id) {
+                    Some(dirs) => dirs,
+                    None => continue,
+                };
+                let rest = path.strip_prefix(&from).expect("Prefix of path");
+                content["path"] = json!(to.join(rest));
+                if !migration.moves.contains(&(from.clone(), to.clone())) {
+                    migration.changes.push(format!(
+                        "{} files moved from {} to {}",
+                        name,
+                        from.display(),
+                        to.display()
+                    ));
+                    migration.moves.push((from, to));
+                }
+            }
+            (id, book)
+        })
+        .collect();
+}
+
+/// The book's directory in `path` and where it goes once named by `id`
+fn rekey(path: &Path, name: &str, id: &str) -> Option<(PathBuf, PathBuf)> {
+    let at = path.components().position(|c| c.as_os_str() == name)?;
+    let from: PathBuf = path.components().take(at + 1).collect();
+    let to = from.with_file_name(id);
+    Some((from, to))
+}
+
+/// Fetched HTML moved out of the library into the page cache
+fn library_v3(value: &mut Value, migration: &mut Migration) {
+    let changes = &mut migration.changes;
+    let books = value.get_mut("books").and_then(Value::as_object_mut);
+    for (id, book) in books.into_iter().flatten() {
+        let index = book.get_mut("index").and_then(Value::as_object_mut);
+        if index.and_then(|i| i.remove("html")).is_some() {
+            changes.push(format!("{} index: dropped embedded html", id));
+        }
+        let chapters = book.get_mut("chapters").and_then(Value::as_object_mut);
+        for (num, ch) in chapters.into_iter().flatten() {
+            let page = ch.get_mut("page").and_then(Value::as_object_mut);
+            if page.and_then(|p| p.remove("html")).is_some() {
+                changes.push(format!(
+                    "{} chapter {}: dropped embedded html",
+                    id, num
+                ));
+            }
+        }
+    }
+}
+
+/// Retrievers used to be saved as a JSON string holding the JSON object
+fn retriever_v1(value: &mut Value, migration: &mut Migration) {
+    let changes = &mut migration.changes;
+    if let Some(inner) = value.as_str().and_then(|s| serde_json::from_str(s).ok())
+    {
+        *value = inner;
+        changes.push("unwrapped string encoded retriever".to_string());
+    }
+    if !value.is_object() {
+        *value = Value::Object(Map::new());
+    }
+}
+
+#[test]
+fn migrate_library_v0() {
+    let cache = std::env::temp_dir()
+        .join(format!("ehound-migrate-{}", std::process::id()));
+    let old_dir = cache.join("Book");
+    std::fs::create_dir_all(old_dir.join("3")).unwrap();
+    std::fs::write(old_dir.join("3").join("0001.jpg"), b"").unwrap();
+    let mut old = json!({
+        "books": { "Book": { "chapters": { "3": {
+            "page": { "location": "", "html": "<html></html>" },
+            "content": {
+                "1": [1, old_dir.join("3")],
+                "2": [2, old_dir.join("3")]
+            }
+        }}}},
+        "location": cache.join("library")
+    });
+    let location = cache.join("library.json");
+    std::fs::write(&location, old.to_string()).unwrap();
+    let migration = LIBRARY.upgrade(&mut old).unwrap();
+    assert_eq!((migration.from, migration.to), (0, 3));
+    assert_eq!(migration.changes.len(), 6);
+    let id = BookId::new("Book", "").to_string();
+    let dir = cache.join(&id);
+    assert_eq!(migration.moves, vec![(old_dir.clone(), dir.clone())]);
+    let content = &old["books"][&id]["chapters"]["3"]["content"];
+    assert_eq!(content["1"]["path"], json!(dir.join("3").join("0001.jpg")));
+    assert_eq!(content["1"]["status"], "Ok");
+    assert_eq!(content["2"]["status"], "Missing");
+    assert_eq!(old["books"][&id]["id"], id.as_str());
+    assert!(old["books"][&id]["chapters"]["3"]["page"]
+        .get("html")
+        .is_none());
+    assert_eq!(version(&old), 3);
+    assert!(LIBRARY.upgrade(&mut old).unwrap().changes.is_empty());
+
+    // Files are only moved when the file itself is migrated
+    LIBRARY.migrate_file(&location, true).unwrap();
+    assert!(old_dir.is_dir() && !dir.exists());
+    LIBRARY.migrate_file(&location, false).unwrap();
+    assert!(!old_dir.exists() && dir.join("3").join("0001.jpg").is_file());
+    assert_eq!(LIBRARY.migrate_file(&location, false).unwrap().from, 3);
+    std::fs::remove_dir_all(cache).ok();
+}
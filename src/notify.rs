@@ -0,0 +1,402 @@
+use crate::{
+    config::Config,
+    library::{BookId, BookName},
+};
+use chrono::{DateTime, Duration as Span, Utc};
+use lettre::{
+    message::Mailbox,
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport,
+    AsyncTransport,
+    Message,
+    Tokio1Executor,
+};
+use reqwest::header::CONTENT_TYPE;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    fs::File,
+    io::{self, BufReader},
+    path::PathBuf,
+    time::Duration,
+};
+use tokio::process::Command;
+
+impl Default for Notify {
+    fn default() -> Self {
+        Self {
+            webhook: None,
+            command: None,
+            email:   None,
+            batch:   Duration::from_secs(10 * 60),
+            muted:   false,
+        }
+    }
+}
+impl Default for Notifier {
+    fn default() -> Self {
+        Self {
+            global:   Notify::default(),
+            books:    BTreeMap::new(),
+            pending:  vec![],
+            location: Config::default().cache.join("notify.json"),
+        }
+    }
+}
+
+/// Where word of new chapters goes, anything left out is the default
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Notify {
+    pub webhook: Option<Webhook>,
+    pub command: Option<Hook>,
+    pub email:   Option<Email>,
+    /// Chapters found within this long of the first one go out together
+    pub batch:   Duration,
+    pub muted:   bool,
+}
+/// Gets the digest POSTed as JSON
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Webhook {
+    pub url:     String,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+/// Run with the digest in `EHOUND_DIGEST` as JSON, a one line summary in
+/// `EHOUND_SUMMARY` and the number of new chapters in `EHOUND_CHAPTERS`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Hook {
+    pub program: String,
+    #[serde(default)]
+    pub args:    Vec<String>,
+}
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Email {
+    pub server:   String,
+    /// The default port of the connection type when unset
+    #[serde(default)]
+    pub port:     Option<u16>,
+    /// Upgrades a plain connection instead of starting with TLS
+    #[serde(default)]
+    pub starttls: bool,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    pub from:     String,
+    pub to:       Vec<String>,
+}
+
+/// Chapters of one book found by a refresh
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Release {
+    pub book:     BookId,
+    pub name:     BookName,
+    pub chapters: Vec<u16>,
+    pub found:    DateTime<Utc>,
+}
+/// Releases sent in one message
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Digest {
+    pub releases: Vec<Release>,
+}
+
+/// Sends out the releases once their batch is over, keeping the ones
+/// waiting on disk so they aren't lost on a restart
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Notifier {
+    /// Set from the config, only the books' own settings are kept
+    #[serde(skip)]
+    pub global: Notify,
+    /// Replace the global settings they set for their book
+    #[serde(default)]
+    books:      BTreeMap<BookId, Notify>,
+    #[serde(default)]
+    pending:    Vec<Release>,
+    #[serde(skip)]
+    location:   PathBuf,
+}
+
+impl Notify {
+    pub fn is_set(&self) -> bool {
+        !self.muted &&
+            (self.webhook.is_some() ||
+                self.command.is_some() ||
+                self.email.is_some())
+    }
+
+    /// Sends the digest everywhere it's set to go, trying each even if an
+    /// earlier one failed
+    pub async fn send(&self, digest: &Digest) -> io::Result<()> {
+        let mut result = Ok(());
+        if let Some(webhook) = &self.webhook {
+            result = result.and(webhook.send(digest).await);
+        }
+        if let Some(hook) = &self.command {
+            result = result.and(hook.run(digest).await);
+        }
+        if let Some(email) = &self.email {
+            result = result.and(email.send(digest).await);
+        }
+        result
+    }
+}
+
+impl Webhook {
+    pub async fn send(&self, digest: &Digest) -> io::Result<()> {
+        let mut req = reqwest::Client::new()
+            .post(&self.url)
+            .header(CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(digest)?);
+        for (name, value) in &self.headers {
+            req = req.header(name.as_str(), value.as_str());
+        }
+        req.send()
+            .await
+            .and_then(|resp| resp.error_for_status())
+            .map(|_| ())
+            .map_err(other)
+    }
+}
+
+impl Hook {
+    pub async fn run(&self, digest: &Digest) -> io::Result<()> {
+        let status = Command::new(&self.program)
+            .args(&self.args)
+            .env("EHOUND_DIGEST", serde_json::to_string(digest)?)
+            .env("EHOUND_SUMMARY", digest.summary())
+            .env("EHOUND_CHAPTERS", digest.chapters().to_string())
+            .status()
+            .await?;
+        match status.success() {
+            true => Ok(()),
+            false => {
+                Err(other(format!("{} exited with {}", self.program, status)))
+            }
+        }
+    }
+}
+
+impl Email {
+    pub async fn send(&self, digest: &Digest) -> io::Result<()> {
+        let mut message = Message::builder()
+            .from(self.from.parse::<Mailbox>().map_err(other)?)
+            .subject(digest.summary());
+        for to in &self.to {
+            message = message.to(to.parse::<Mailbox>().map_err(other)?);
+        }
+        let message = message.body(digest.to_string()).map_err(other)?;
+        let mut smtp = match self.starttls {
+            true => {
+                AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&self.server)
+            }
+            false => AsyncSmtpTransport::<Tokio1Executor>::relay(&self.server),
+        }
+        .map_err(other)?;
+        if let Some(port) = self.port {
+            smtp = smtp.port(port);
+        }
+        if let (Some(user), Some(pass)) = (&self.username, &self.password) {
+            smtp = smtp.credentials(Credentials::new(user.clone(), pass.clone()));
+        }
+        smtp.build().send(message).await.map(|_| ()).map_err(other)
+    }
+}
+
+impl Digest {
+    pub fn chapters(&self) -> usize {
+        self.releases.iter().map(|r| r.chapters.len()).sum()
+    }
+
+    pub fn summary(&self) -> String {
+        match self.releases.as_slice() {
+            [release] => format!(
+                "{} new chapters of {}",
+                release.chapters.len(),
+                release.name.as_str()
+            ),
+            releases => format!(
+                "{} new chapters in {} books",
+                self.chapters(),
+                releases.len()
+            ),
+        }
+    }
+
+    /// Adds the release, to the one of the same book if there's one
+    fn add(&mut self, release: Release) {
+        match self.releases.iter_mut().find(|r| r.book == release.book) {
+            Some(r) => {
+                r.chapters.extend(release.chapters);
+                r.chapters.sort_unstable();
+                r.chapters.dedup();
+            }
+            None => self.releases.push(release),
+        }
+    }
+}
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for release in &self.releases {
+            let chapters = release
+                .chapters
+                .iter()
+                .map(|c| c.to_string())
+                .collect::<Vec<_>>();
+            writeln!(f, "{}: {}", release.name.as_str(), chapters.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+impl Notifier {
+    /// The settings and releases saved at `location`, or none
+    pub fn open(location: PathBuf) -> io::Result<Self> {
+        let mut notifier: Self = match File::open(&location) {
+            Ok(file) => serde_json::from_reader(BufReader::new(file))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Self::default(),
+            Err(e) => return Err(e),
+        };
+        notifier.location = location;
+        Ok(notifier)
+    }
+
+    /// Written next to the location and renamed into place
+    pub fn save(&self) -> io::Result<()> {
+        self.location
+            .parent()
+            .map(std::fs::create_dir_all)
+            .transpose()?;
+        let tmp = self.location.with_extension("json.tmp");
+        serde_json::to_writer(File::create(&tmp)?, self)?;
+        std::fs::rename(tmp, &self.location)
+    }
+
+    /// Settings for the book alone, `None` goes back to the global ones
+    pub fn set_book(&mut self, book: &BookId, notify: Option<Notify>) {
+        match notify {
+            Some(notify) => self.books.insert(book.clone(), notify),
+            None => self.books.remove(book),
+        };
+    }
+
+    /// What applies to the book, its own settings over the global ones
+    pub fn settings(&self, book: &BookId) -> Notify {
+        match self.books.get(book) {
+            Some(own) => Notify {
+                webhook: own.webhook.clone().or(self.global.webhook.clone()),
+                command: own.command.clone().or(self.global.command.clone()),
+                email: own.email.clone().or(self.global.email.clone()),
+                ..own.clone()
+            },
+            None => self.global.clone(),
+        }
+    }
+
+    /// Holds on to the release until its batch is over, if anyone is to
+    /// hear about it
+    pub fn record(&mut self, release: Release) {
+        if !release.chapters.is_empty() && self.settings(&release.book).is_set() {
+            self.pending.push(release);
+        }
+    }
+
+    pub fn pending(&self) -> &[Release] { &self.pending }
+
+    /// Puts back the releases of a digest that couldn't be sent, they go
+    /// out with the next one
+    pub fn restore(&mut self, digest: Digest) {
+        self.pending.extend(digest.releases);
+    }
+
+    /// Takes the digests whose batch is over at `now`, or every one of them
+    /// when `all` is set, one for each place they go to
+    pub fn take(
+        &mut self, now: DateTime<Utc>, all: bool,
+    ) -> Vec<(Notify, Digest)> {
+        let mut groups: Vec<(Notify, Digest, DateTime<Utc>)> = vec![];
+        for release in std::mem::take(&mut self.pending) {
+            let notify = self.settings(&release.book);
+            let found = release.found;
+            match groups.iter_mut().find(|(n, ..)| *n == notify) {
+                Some((_, digest, first)) => {
+                    *first = (*first).min(found);
+                    digest.add(release);
+                }
+                None => {
+                    let mut digest = Digest::default();
+                    digest.add(release);
+                    groups.push((notify, digest, found));
+                }
+            }
+        }
+        let (due, waiting): (Vec<_>, Vec<_>) =
+            groups.into_iter().partition(|(notify, _, first)| {
+                let batch =
+                    Span::from_std(notify.batch).unwrap_or_else(|_| Span::zero());
+                all || *first + batch <= now
+            });
+        self.pending = waiting
+            .into_iter()
+            .flat_map(|(_, digest, _)| digest.releases)
+            .collect();
+        due.into_iter()
+            .map(|(notify, digest, _)| (notify, digest))
+            .collect()
+    }
+}
+
+fn other(e: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+#[test]
+fn notify_batch() {
+    let mut notifier = Notifier::default();
+    notifier.global.command = Some(Hook {
+        program: "true".to_string(),
+        args:    vec![],
+    });
+    let (a, b) = (BookId::from("a".to_string()), BookId::from("b".to_string()));
+    notifier.set_book(
+        &b,
+        Some(Notify {
+            muted: true,
+            ..Default::default()
+        }),
+    );
+    let start = Utc::now();
+    for n in 1..=50 {
+        notifier.record(Release {
+            book:     a.clone(),
+            name:     "A".to_string().into(),
+            chapters: vec![n],
+            found:    start + Span::seconds(n as i64),
+        });
+    }
+    notifier.record(Release {
+        book:     b.clone(),
+        name:     "B".to_string().into(),
+        chapters: vec![1],
+        found:    start,
+    });
+    assert_eq!(notifier.pending().len(), 50);
+    assert!(notifier.take(start + Span::minutes(5), false).is_empty());
+
+    // All 50 chapters go out in a single message
+    let due = notifier.take(start + Span::minutes(11), false);
+    assert_eq!(due.len(), 1);
+    assert_eq!(due[0].1.chapters(), 50);
+    assert_eq!(due[0].1.summary(), "50 new chapters of A");
+    assert!(notifier.pending().is_empty());
+
+    // A digest that couldn't be sent is kept for the next try
+    let (_, digest) = due.into_iter().next().unwrap();
+    notifier.restore(digest);
+    assert_eq!(notifier.take(start + Span::minutes(11), false).len(), 1);
+
+    // Settings written by hand may leave anything out
+    let notify: Notify = serde_json::from_str(r#"{"muted": true}"#).unwrap();
+    assert_eq!(notify.batch, Notify::default().batch);
+}
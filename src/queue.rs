@@ -0,0 +1,284 @@
+use crate::{config::Config, library::BookId};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::File,
+    io::{self, BufReader},
+    path::PathBuf,
+};
+
+impl Default for Queue {
+    fn default() -> Self {
+        Self {
+            next_id:  0,
+            jobs:     BTreeMap::new(),
+            paused:   BTreeSet::new(),
+            location: Config::default().cache.join("queue.json"),
+        }
+    }
+}
+impl Default for Priority {
+    fn default() -> Self { Priority::Normal }
+}
+
+/// Downloads waiting to be done, kept on disk so they survive restarts
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Queue {
+    next_id:  u64,
+    jobs:     BTreeMap<u64, Job>,
+    /// Books none of whose jobs are handed out
+    paused:   BTreeSet<BookId>,
+    #[serde(skip)]
+    location: PathBuf,
+}
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Job {
+    pub id:       u64,
+    pub book:     BookId,
+    pub task:     Task,
+    pub priority: Priority,
+    pub state:    JobState,
+    pub added:    DateTime<Utc>,
+}
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Task {
+    /// Looks up the chapters of the book and queues the ones it's missing
+    Book,
+    Chapter {
+        url: String,
+    },
+    Page {
+        chapter: u16,
+        num:     u16,
+        url:     String,
+    },
+}
+/// Higher goes first, jobs of the same priority go in the order they came
+#[derive(
+    Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize,
+)]
+pub enum Priority {
+    Backfill,
+    Normal,
+    /// What the user is about to read
+    Reading,
+}
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum JobState {
+    Queued,
+    Running,
+    Paused,
+    Done,
+    Failed(String),
+    Cancelled,
+}
+
+impl Queue {
+    /// The queue saved at `location`, or an empty one. Jobs that were
+    /// running when the process stopped are queued again.
+    pub fn open(location: PathBuf) -> io::Result<Self> {
+        let mut queue = match File::open(&location) {
+            Ok(file) => serde_json::from_reader(BufReader::new(file))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Queue::default(),
+            Err(e) => return Err(e),
+        };
+        queue.location = location;
+        queue
+            .jobs
+            .values_mut()
+            .filter(|j| j.state == JobState::Running)
+            .for_each(|j| j.state = JobState::Queued);
+        Ok(queue)
+    }
+
+    /// Written next to the location and renamed into place
+    pub fn save(&self) -> io::Result<()> {
+        self.location
+            .parent()
+            .map(std::fs::create_dir_all)
+            .transpose()?;
+        let tmp = self.location.with_extension("json.tmp");
+        serde_json::to_writer(File::create(&tmp)?, self)?;
+        std::fs::rename(tmp, &self.location)
+    }
+
+    /// Queues the task unless it's already waiting, in which case it only
+    /// moves up to `priority`. Returns the job's id.
+    pub fn push(&mut self, book: BookId, task: Task, priority: Priority) -> u64 {
+        let waiting = self.jobs.values_mut().find(|j| {
+            j.book == book &&
+                j.task == task &&
+                matches!(j.state, JobState::Queued | JobState::Paused)
+        });
+        if let Some(job) = waiting {
+            job.priority = job.priority.max(priority);
+            return job.id;
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.jobs.insert(id, Job {
+            id,
+            book,
+            task,
+            priority,
+            state: JobState::Queued,
+            added: Utc::now(),
+        });
+        id
+    }
+
+    /// Hands out the most urgent queued job, marking it running
+    pub fn next(&mut self) -> Option<Job> { self.take(|_| true) }
+
+    /// Same as next(), among the jobs in `ids` only
+    pub fn next_of(&mut self, ids: &BTreeSet<u64>) -> Option<Job> {
+        self.take(|j| ids.contains(&j.id))
+    }
+
+    /// Records how the job went, unless it was cancelled meanwhile
+    pub fn finish(&mut self, id: u64, result: Result<(), String>) {
+        if let Some(job) = self.jobs.get_mut(&id) {
+            if job.state == JobState::Running {
+                job.state = match result {
+                    Ok(()) => JobState::Done,
+                    Err(e) => JobState::Failed(e),
+                };
+            }
+        }
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Job> { self.jobs.get(&id) }
+
+    pub fn jobs(&self) -> impl Iterator<Item = &Job> { self.jobs.values() }
+
+    /// Jobs still to be done
+    pub fn pending(&self) -> usize {
+        self.jobs
+            .values()
+            .filter(|j| matches!(j.state, JobState::Queued | JobState::Running))
+            .count()
+    }
+
+    pub fn prioritize(&mut self, id: u64, priority: Priority) {
+        self.jobs.get_mut(&id).map(|j| j.priority = priority);
+    }
+
+    pub fn pause(&mut self, id: u64) {
+        self.set(id, JobState::Queued, JobState::Paused)
+    }
+
+    pub fn resume(&mut self, id: u64) {
+        self.set(id, JobState::Paused, JobState::Queued)
+    }
+
+    /// Cancels the job, a running one finishes but its result is dropped
+    pub fn cancel(&mut self, id: u64) {
+        if let Some(job) = self.jobs.get_mut(&id) {
+            if matches!(
+                job.state,
+                JobState::Queued | JobState::Running | JobState::Paused
+            ) {
+                job.state = JobState::Cancelled;
+            }
+        }
+    }
+
+    pub fn pause_book(&mut self, book: &BookId) {
+        self.paused.insert(book.clone());
+    }
+
+    pub fn resume_book(&mut self, book: &BookId) { self.paused.remove(book); }
+
+    pub fn cancel_book(&mut self, book: &BookId) {
+        let ids = self.ids(|j| j.book == *book);
+        ids.into_iter().for_each(|id| self.cancel(id));
+    }
+
+    /// Forgets jobs that are done or cancelled, and failed ones whose task
+    /// was queued again since
+    pub fn prune(&mut self) {
+        let retried = self.ids(|j| {
+            matches!(j.state, JobState::Failed(_)) &&
+                self.jobs.values().any(|k| {
+                    k.id > j.id && k.book == j.book && k.task == j.task
+                })
+        });
+        self.jobs.retain(|id, j| match j.state {
+            JobState::Done | JobState::Cancelled => false,
+            JobState::Failed(_) => !retried.contains(id),
+            _ => true,
+        });
+    }
+
+    fn take(&mut self, f: impl Fn(&Job) -> bool) -> Option<Job> {
+        let paused = &self.paused;
+        let job = self
+            .jobs
+            .values_mut()
+            .filter(|j| j.state == JobState::Queued && !paused.contains(&j.book))
+            .filter(|j| f(j))
+            .max_by(|a, b| a.priority.cmp(&b.priority).then(b.id.cmp(&a.id)))?;
+        job.state = JobState::Running;
+        Some(job.clone())
+    }
+
+    fn ids(&self, f: impl Fn(&Job) -> bool) -> Vec<u64> {
+        self.jobs.values().filter(|j| f(j)).map(|j| j.id).collect()
+    }
+
+    fn set(&mut self, id: u64, from: JobState, to: JobState) {
+        self.jobs
+            .get_mut(&id)
+            .filter(|j| j.state == from)
+            .map(|j| j.state = to);
+    }
+}
+
+#[test]
+fn queue_order() {
+    let location = std::env::temp_dir()
+        .join(format!("ehound-queue-{}.json", std::process::id()));
+    let mut queue = Queue::open(location.clone()).unwrap();
+    let (a, b) = (BookId::from("a".to_string()), BookId::from("b".to_string()));
+    let chapter = |n| Task::Chapter {
+        url: format!("https://example.com/book/chapter-{}", n),
+    };
+    let backfill = queue.push(a.clone(), chapter(1), Priority::Backfill);
+    let normal = queue.push(a.clone(), chapter(2), Priority::Normal);
+    let other = queue.push(b.clone(), chapter(3), Priority::Normal);
+    assert_eq!(
+        queue.push(a.clone(), chapter(1), Priority::Reading),
+        backfill
+    );
+    assert_eq!(queue.next().unwrap().id, backfill);
+    queue.pause_book(&a);
+    assert_eq!(queue.next().unwrap().id, other);
+    assert_eq!(queue.next(), None);
+    queue.resume_book(&a);
+    queue.save().unwrap();
+
+    // Running jobs are picked up again after a restart
+    let mut queue = Queue::open(location.clone()).unwrap();
+    assert_eq!(queue.get(other).unwrap().state, JobState::Queued);
+    queue.cancel(normal);
+    assert_eq!(queue.next().unwrap().id, backfill);
+    assert_eq!(queue.next().unwrap().id, other);
+
+    // Finished jobs are forgotten, failed ones until they're queued again
+    queue.finish(backfill, Err("timed out".to_string()));
+    queue.finish(other, Ok(()));
+    queue.prune();
+    assert!(queue.get(backfill).is_some() && queue.get(other).is_none());
+    let again = queue.push(a.clone(), chapter(1), Priority::Backfill);
+    queue.prune();
+    assert!(queue.get(backfill).is_none() && queue.get(again).is_some());
+
+    // Only the given jobs are handed out, however urgent the others are
+    let urgent = queue.push(b.clone(), chapter(4), Priority::Reading);
+    let only = std::iter::once(again).collect();
+    assert_eq!(queue.next_of(&only).unwrap().id, again);
+    assert_eq!(queue.next_of(&only), None);
+    assert_eq!(queue.next().unwrap().id, urgent);
+    std::fs::remove_file(location).ok();
+}
@@ -1,155 +1,588 @@
 use crate::{
-    library::{Chapter, Content},
+    cache::{Mode, PageCache},
+    config::{Config, ANY},
+    events::{Event, Events},
+    fetch::{self, Fetcher, Http},
+    library::{digest, BookId, Chapter, Content},
+    limit::{Limited, Limits, RateLimiter},
+    media::{self, Format},
+    migrate,
+    retry::{Retry, RetryPolicy},
+    schedule::{Parallelism, Scheduler, Slot},
     source::Source,
-    CACHE,
 };
-use core::slice::SlicePattern;
-use futures::future::join_all;
+use futures::{future::join_all, StreamExt};
 use reqwest::{
-    header::{HeaderMap, REFERER},
+    header::{
+        HeaderMap,
+        HeaderValue,
+        ETAG,
+        IF_RANGE,
+        LAST_MODIFIED,
+        RANGE,
+        REFERER,
+    },
     Client,
+    StatusCode,
     Url,
 };
 use serde::{Deserialize, Serialize};
 use std::{
     collections::BTreeMap,
     fs::File,
-    io::BufReader,
+    io::{self, BufReader, Write},
     ops::Deref,
-    path::PathBuf,
+    path::{Path, PathBuf},
+    sync::Arc,
 };
 
 impl Default for Retriever {
     fn default() -> Self {
-        let mut h = BTreeMap::new();
-        let m = "readmanganato.com".to_string();
-        h.insert(m.clone(), Headers::default());
-        h.get_mut(&m)
-            .unwrap()
-            .headers
-            .insert(REFERER, "https://readmanganato.com/".parse().unwrap());
-        Self {
-            client:   Client::new(),
-            headers:  h,
-            location: CACHE.to_string() + "/retriever.json",
-        }
-    }
-}
-impl Default for Headers {
-    fn default() -> Self {
-        let mut hm = HeaderMap::new();
-        hm.insert(REFERER, "https://manganato.com/".parse().unwrap());
-        Self { headers: hm }
+        let mut dl = Self {
+            version:     migrate::RETRIEVER.current(),
+            retry:       BTreeMap::new(),
+            limits:      BTreeMap::new(),
+            limiter:     Arc::new(RateLimiter::default()),
+            parallelism: Parallelism::default(),
+            scheduler:   Arc::new(Scheduler::default()),
+            events:      Events::default(),
+            fetcher:     fetch::shared(),
+            headers:     BTreeMap::new(),
+            config:      Arc::new(Config::default()),
+            location:    String::new(),
+        };
+        dl.configure(dl.config.clone());
+        dl
     }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Retriever {
-    headers:  BTreeMap<String, Headers>,
+    #[serde(default)]
+    version:     u32,
+    headers:     BTreeMap<String, Headers>,
+    /// Retry policies by domain, the default policy for the rest
+    #[serde(default)]
+    pub retry:   BTreeMap<String, RetryPolicy>,
+    /// Rate limits by domain, the default limits for the rest
+    #[serde(default)]
+    limits:      BTreeMap<String, Limits>,
+    #[serde(skip)]
+    limiter:     Arc<RateLimiter>,
+    #[serde(default)]
+    parallelism: Parallelism,
+    #[serde(skip)]
+    scheduler:   Arc<Scheduler>,
     #[serde(skip)]
-    client:   Client,
+    events:      Events,
+    #[serde(skip, default = "fetch::shared")]
+    fetcher:     Arc<dyn Fetcher>,
     #[serde(skip)]
-    location: String,
+    config:      Arc<Config>,
+    #[serde(skip)]
+    location:    String,
 }
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
 struct Headers {
     #[serde(with = "http_serde::header_map")]
     pub headers: HeaderMap,
 }
 
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum DownloadError {
+    Http {
+        url:    String,
+        status: Option<u16>,
+        reason: String,
+    },
+    NotAnImage {
+        url:  String,
+        mime: Option<String>,
+    },
+    /// The file couldn't be written or read back
+    Io {
+        path:   String,
+        reason: String,
+    },
+    /// Offline and the response was never cached
+    Offline {
+        url: String,
+    },
+    /// Not something that can be downloaded at all
+    InvalidUrl {
+        url:    String,
+        reason: String,
+    },
+    /// Still failing after every attempt the retry policy allows
+    Exhausted {
+        url:      String,
+        attempts: usize,
+        last:     Box<DownloadError>,
+    },
+}
+
+/// A downloaded chapter along with why its missing pages are missing
+#[derive(Debug, Clone)]
+pub struct ChapterDownload {
+    pub chapter: Chapter,
+    pub errors:  BTreeMap<u16, DownloadError>,
+}
+
 impl Retriever {
-    pub async fn fetch(&self, url: String) -> Source {
-        Source::from(url).refresh().await
+    /// Retriever set up by `config`, which it hands on to everything it
+    /// downloads
+    pub fn new(config: Arc<Config>) -> Self {
+        let mut dl = Self::default();
+        dl.configure(config);
+        dl
+    }
+
+    /// Downloads through `fetcher` from now on, pages fetched through this
+    /// retriever included
+    pub fn with_fetcher(mut self, fetcher: Arc<dyn Fetcher>) -> Self {
+        self.fetcher = fetcher;
+        self
+    }
+
+    /// Replaces the rate limits of `domain`, taking effect for requests
+    /// made from now on
+    pub fn set_limits(&mut self, domain: &str, limits: Limits) {
+        self.limits.insert(domain.to_string(), limits);
+        self.limiter = Arc::new(RateLimiter::new(self.limits.clone()));
+    }
+
+    /// Takes the headers, limits, parallelism and user agent of `config`
+    /// over its own, and keeps its own settings in its cache
+    pub fn configure(&mut self, config: Arc<Config>) {
+        self.headers.extend(config.sites.keys().map(|domain| {
+            let headers = config.site(domain).header_map().unwrap_or_default();
+            (domain.clone(), Headers { headers })
+        }));
+        self.limits.insert(ANY.to_string(), config.limits);
+        for (domain, site) in &config.sites {
+            if let Some(limits) = site.limits {
+                self.limits.insert(domain.clone(), limits);
+            }
+        }
+        self.limiter = Arc::new(RateLimiter::new(self.limits.clone()));
+        self.set_parallelism(config.parallelism);
+        if let Some(agent) = &config.user_agent {
+            match Client::builder()
+                .cookie_store(true)
+                .user_agent(agent)
+                .build()
+            {
+                Ok(client) => self.fetcher = Arc::new(Http::new(client)),
+                Err(e) => {
+                    eprintln!("Couldn't use the user agent {}: {}", agent, e)
+                }
+            }
+        }
+        self.location = config
+            .cache
+            .join("retriever.json")
+            .to_string_lossy()
+            .into_owned();
+        self.config = config;
+    }
+
+    pub fn config(&self) -> &Config { &self.config }
+
+    /// Where this retriever and its clones report progress
+    pub fn events(&self) -> &Events { &self.events }
+
+    /// Changes how many downloads run at once, clones of this retriever
+    /// included
+    pub fn set_parallelism(&mut self, parallelism: Parallelism) {
+        self.parallelism = parallelism;
+        self.scheduler.configure(parallelism);
+    }
+
+    /// Waits until downloading `url` for `book` fits the parallelism limits
+    pub async fn slot(&self, book: &BookId, url: &str) -> Slot {
+        self.scheduler.slot(book, url).await
     }
 
-    pub async fn chapter(&self, src: Source, visual: Option<bool>) -> Chapter {
+    /// The fetcher with this retriever's rate limits and retry policies on
+    /// top, every retry waits for its turn again
+    pub fn fetcher(&self) -> Arc<dyn Fetcher> {
+        Arc::new(Retry {
+            inner:    Arc::new(Limited {
+                inner:   self.fetcher.clone(),
+                limiter: self.limiter.clone(),
+                events:  self.events.clone(),
+            }),
+            policies: self.retry.clone(),
+        })
+    }
+
+    /// The source with this retriever's fetcher and config, not downloaded
+    /// yet
+    pub fn source(&self, src: impl Into<Source>) -> Source {
+        src.into()
+            .with_fetcher(self.fetcher())
+            .with_config(self.config.clone())
+    }
+
+    pub async fn try_fetch(&self, url: String) -> Result<Source, DownloadError> {
+        self.source(url).try_refresh().await
+    }
+
+    /// Downloads the chapter, keeping the pages of `known` that are still
+    /// intact on disk
+    pub async fn chapter(
+        &self, book: &BookId, mut src: Source, visual: Option<bool>,
+        known: Option<&Chapter>,
+    ) -> ChapterDownload {
         let mut ch = Chapter::default();
+        let mut errors = BTreeMap::new();
+        let chapter = src.place.1;
         // TODO: to be investigated
         ch.pos = src.place.0;
-        let vis = visual.unwrap_or(src.check_visual().unwrap());
-        let path = &PathBuf::from(CACHE).join(&src.title().deref());
-        match vis {
-            true => {
-                join_all(
-                    src.images_batch()
-                        .unwrap_or_default()
-                        .iter()
-                        .map(|s| self.content(s, true, path)),
-                )
-                .await
-                .iter()
-                .cloned()
-                .for_each(|mut content| {
-                    content.0 = src.place.1;
-                    ch.add_content(content);
-                });
+        let vis = visual.or_else(|| src.check_visual()).unwrap_or_default();
+        let dir = &self
+            .config
+            .cache
+            .join(book.deref())
+            .join(src.place.1.to_string());
+        let urls = match vis {
+            true => src.images_batch().unwrap_or_default(),
+            false => vec![src.location.clone()],
+        };
+        join_all(urls.iter().enumerate().map(|(i, s)| async move {
+            let num = i as u16;
+            match known.and_then(|k| k.pages().get(&num)) {
+                Some(cnt) if cnt.url == *s && cnt.downloaded() => {
+                    return (cnt.clone(), None);
+                }
+                _ => {}
             }
-            false => {
-                let cnt = self.content(&src.location, false, path).await;
-                ch.add_content(cnt);
+            let _slot = self.slot(book, s).await;
+            let page = known.and_then(|k| k.pages().get(&num));
+            match self.content(s, num, vis, dir, page).await {
+                Ok(cnt) => {
+                    self.events.emit(Event::PageDownloaded {
+                        book: book.clone(),
+                        chapter,
+                        page: num,
+                        bytes: cnt.size,
+                    });
+                    (cnt, None)
+                }
+                Err(e) => {
+                    let mut cnt = Content::new(num, s.clone());
+                    cnt.fail(e.to_string());
+                    (cnt, Some(e))
+                }
             }
-        };
+        }))
+        .await
+        .into_iter()
+        .for_each(|(content, error)| {
+            error.map(|e| errors.insert(content.num, e));
+            ch.add_content(content);
+        });
+        src.release();
         ch.page = src;
-        ch
+        self.events.emit(Event::ChapterCompleted {
+            book: book.clone(),
+            chapter,
+            pages: ch.pages().len(),
+            failed: errors.len(),
+        });
+        ChapterDownload {
+            chapter: ch,
+            errors,
+        }
     }
 
+    /// Downloads the page into `dir`. A file already there is only kept if
+    /// it's the one `known` recorded for the same url.
     pub async fn content(
-        &self, source: &String, visual: bool, path: &PathBuf,
-    ) -> Content {
-        let src: Source = self.fetch(source.to_string()).await;
-        let mut cnt = Content::default();
-        cnt.0 = src.place.0;
-        cnt.1 = cnt.1.join(path).join(&src.place.1.to_string());
+        &self, source: &String, num: u16, visual: bool, dir: &PathBuf,
+        known: Option<&Content>,
+    ) -> Result<Content, DownloadError> {
+        let mut cnt = Content::new(num, source.clone());
+        cnt.referer = self
+            .get_headers(source)
+            .get(REFERER)
+            .and_then(|r| r.to_str().ok())
+            .map(|r| r.to_string());
+        let expected = known
+            .filter(|k| k.url == *source)
+            .and_then(|k| k.sha256.clone());
+        let intact =
+            |data: &[u8]| expected.as_deref() == Some(digest(data).as_str());
         match visual {
             true => {
-                cnt.save(
-                    &self
-                        .client
-                        .get(source)
-                        .headers(self.get_headers(source))
-                        .send()
-                        .await
-                        .ok()
-                        .unwrap()
-                        .bytes()
-                        .await
-                        .ok()
-                        .unwrap()
-                        .as_slice(),
-                );
+                let (data, format) = match cached(dir, num) {
+                    Some((data, format)) if intact(&data) => (data, format),
+                    stale => {
+                        if let Some((_, format)) = stale {
+                            let path =
+                                dir.join(format!("{:04}.{}", num, format.ext()));
+                            std::fs::remove_file(path).ok();
+                        }
+                        let part = &dir.join(format!("{:04}.part", num));
+                        // Retries are left to the fetcher's retry policies
+                        let found = self.image(source, part).await?;
+                        let path =
+                            dir.join(format!("{:04}.{}", num, found.1.ext()));
+                        std::fs::rename(part, &path)
+                            .map_err(|e| DownloadError::io(&path, e))?;
+                        found
+                    }
+                };
+                cnt.path = dir.join(format!("{:04}.{}", num, format.ext()));
+                cnt.mime = Some(format.mime().to_string());
+                cnt.dimensions = media::dimensions(&data);
+                cnt.record(&data);
             }
             false => {
-                println!("{:?}", &src.text());
-                let text = src.text().unwrap_or_default().join("\n\n");
-                cnt.save(text.as_bytes());
+                cnt.path = dir.join(format!("{:04}.json", num));
+                cnt.mime = Some("application/json".to_string());
+                let kept = std::fs::read(&cnt.path).ok().filter(|d| intact(d));
+                match kept.zip(cnt.load_text()) {
+                    Some((data, text)) => {
+                        cnt.words = Some(text.meta.words);
+                        cnt.record(&data);
+                    }
+                    None => {
+                        let src = self.try_fetch(source.to_string()).await?;
+                        cnt.save_text(&src.text_document().unwrap_or_default())
+                            .map_err(|e| DownloadError::io(&cnt.path, e))?;
+                    }
+                }
+            }
+        }
+        Ok(cnt)
+    }
+
+    /// Downloads an image into `part`, resuming from whatever an interrupted
+    /// download left there if the server still has the same file, and
+    /// rejects anything that doesn't look like one. What fails past the
+    /// fetcher's own retries, a page served instead of the image or a body
+    /// cut short, is tried again by the same policy.
+    pub async fn image(
+        &self, url: &String, part: &PathBuf,
+    ) -> Result<(Vec<u8>, Format), DownloadError> {
+        let policy = RetryPolicy::of(&self.retry, url);
+        let mut attempt = 1;
+        loop {
+            let last = match self.fetch_image(url, part).await {
+                Err(e) if e.retryable() && self.config.mode != Mode::Offline => e,
+                res => return res,
+            };
+            if attempt >= policy.attempts {
+                return Err(DownloadError::Exhausted {
+                    url:      url.clone(),
+                    attempts: attempt,
+                    last:     Box::new(last),
+                });
+            }
+            tokio::time::sleep(policy.delay(attempt)).await;
+            attempt += 1;
+        }
+    }
+
+    async fn fetch_image(
+        &self, url: &String, part: &PathBuf,
+    ) -> Result<(Vec<u8>, Format), DownloadError> {
+        if let Some(dir) = part.parent() {
+            std::fs::create_dir_all(dir)
+                .map_err(|e| DownloadError::io(dir, e))?;
+        }
+        // The ETag or Last-Modified of what's in the part file
+        let tag = part.with_extension("part.tag");
+        let cache = PageCache::new(&self.config.cache);
+        if self.config.mode == Mode::Offline {
+            let (page, data) = cache
+                .replay(url)
+                .map_err(|_| DownloadError::Offline { url: url.clone() })?;
+            std::fs::write(part, &data)
+                .map_err(|e| DownloadError::io(part, e))?;
+            return Format::sniff(&data).map(|f| (data, f)).ok_or(
+                DownloadError::NotAnImage {
+                    url:  url.clone(),
+                    mime: page.mime,
+                },
+            );
+        }
+        let offset = std::fs::metadata(part).map(|m| m.len()).unwrap_or(0);
+        let validator = std::fs::read_to_string(&tag)
+            .ok()
+            .and_then(|t| t.parse::<HeaderValue>().ok())
+            .filter(|_| offset > 0);
+        let mut headers = self.get_headers(url);
+        // Without a validator there's no telling the part is still the
+        // same file, so it's downloaded again from the start
+        if let Some(validator) = validator {
+            headers.insert(RANGE, format!("bytes={}-", offset).parse().unwrap());
+            headers.insert(IF_RANGE, validator);
+        }
+        let mut resp = self.fetcher().get(url, headers).await?;
+        let (status, headers) = (resp.status, resp.headers.clone());
+        let mime = resp.content_type();
+        // The part file already holds the whole image
+        if resp.status != StatusCode::RANGE_NOT_SATISFIABLE.as_u16() {
+            resp = resp.error_for_status(url)?;
+            let partial = resp.status == StatusCode::PARTIAL_CONTENT.as_u16();
+            if !partial {
+                let etag = resp
+                    .headers
+                    .get(ETAG)
+                    .filter(|e| !e.as_bytes().starts_with(b"W/"));
+                match etag.or_else(|| resp.headers.get(LAST_MODIFIED)) {
+                    Some(v) => std::fs::write(&tag, v.as_bytes())
+                        .map_err(|e| DownloadError::io(&tag, e))?,
+                    None => std::fs::remove_file(&tag).unwrap_or_default(),
+                }
+            }
+            let mut file = File::with_options()
+                .create(true)
+                .write(true)
+                .append(partial)
+                .truncate(!partial)
+                .open(part)
+                .map_err(|e| DownloadError::io(part, e))?;
+            while let Some(chunk) = resp.body.next().await {
+                file.write_all(&chunk?)
+                    .map_err(|e| DownloadError::io(part, e))?;
+            }
+        }
+        let data = std::fs::read(part).map_err(|e| DownloadError::io(part, e))?;
+        std::fs::remove_file(&tag).ok();
+        let markup = data
+            .iter()
+            .find(|b| !b.is_ascii_whitespace())
+            .map_or(true, |&b| b == b'<');
+        match Format::sniff(&data).or_else(|| {
+            (!markup)
+                .then(|| mime.as_deref().and_then(Format::from_mime))
+                .flatten()
+        }) {
+            Some(format) => {
+                if self.config.mode == Mode::Record {
+                    cache
+                        .put_response(url, status, &headers, &data)
+                        .map_err(|e| eprintln!("Couldn't cache {}: {}", url, e))
+                        .ok();
+                }
+                Ok((data, format))
+            }
+            None => {
+                std::fs::remove_file(part).ok();
+                Err(DownloadError::NotAnImage {
+                    url: url.clone(),
+                    mime,
+                })
             }
         }
-        cnt
     }
 
     pub async fn save(&self) {
         let file = File::with_options()
             .write(true)
             .create(true)
+            .truncate(true)
             .open(&self.location)
             .unwrap();
-        serde_json::to_writer(&file, &serde_json::to_string(&self).unwrap())
-            .unwrap();
+        serde_json::to_writer(&file, &self).unwrap();
     }
 
-    pub async fn load(&mut self) {
-        let reader = BufReader::new(File::open(&self.location).unwrap());
-        let contents: String = serde_json::from_reader(reader)
-            .expect("The json has most likely been corrupted.");
-        let Self { headers: h, .. } = serde_json::from_str(&contents).unwrap();
-        self.headers = h;
+    /// Takes the settings saved in its cache, those of the config still
+    /// win over them. Fails on files it can't read, including those written
+    /// by a newer version.
+    pub async fn load(&mut self) -> io::Result<()> {
+        let reader = BufReader::new(File::open(&self.location)?);
+        let mut value = serde_json::from_reader(reader)?;
+        migrate::RETRIEVER.upgrade(&mut value)?;
+        let Self {
+            headers,
+            retry,
+            limits,
+            ..
+        } = serde_json::from_value(value)?;
+        self.headers = headers;
+        self.retry = retry;
+        self.limits = limits;
+        self.configure(self.config.clone());
+        Ok(())
     }
 
     fn get_headers(&self, src: &String) -> HeaderMap {
-        self.headers
-            .get(src.parse::<Url>().unwrap().domain().unwrap())
+        let url = src.parse::<Url>().ok();
+        url.as_ref()
+            .and_then(|u| self.headers.get(u.host_str()?))
+            .or_else(|| self.headers.get(ANY))
             .cloned()
             .unwrap_or_default()
             .headers
     }
 }
+
+/// A finished image left in the cache directory by an earlier run
+fn cached(dir: &PathBuf, num: u16) -> Option<(Vec<u8>, Format)> {
+    Format::ALL.iter().find_map(|f| {
+        let data =
+            std::fs::read(dir.join(format!("{:04}.{}", num, f.ext()))).ok()?;
+        let format = Format::sniff(&data).filter(|s| s == f)?;
+        Some((data, format))
+    })
+}
+
+impl DownloadError {
+    fn io(path: &Path, e: std::io::Error) -> Self {
+        DownloadError::Io {
+            path:   path.display().to_string(),
+            reason: e.to_string(),
+        }
+    }
+
+    pub fn retryable(&self) -> bool {
+        match self {
+            DownloadError::Http { status, .. } => {
+                status.map_or(true, RetryPolicy::transient)
+            }
+            DownloadError::NotAnImage { .. } => true,
+            DownloadError::Io { .. } => false,
+            DownloadError::Offline { .. } => false,
+            DownloadError::InvalidUrl { .. } => false,
+            DownloadError::Exhausted { .. } => false,
+        }
+    }
+}
+impl std::fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DownloadError::Http { url, reason, .. } => {
+                write!(f, "Couldn't download {}: {}", url, reason)
+            }
+            DownloadError::NotAnImage { url, mime } => write!(
+                f,
+                "{} isn't an image ({})",
+                url,
+                mime.as_deref().unwrap_or("unknown type")
+            ),
+            DownloadError::Io { path, reason } => {
+                write!(f, "Couldn't write {}: {}", path, reason)
+            }
+            DownloadError::Offline { url } => {
+                write!(f, "{} isn't in the page cache", url)
+            }
+            DownloadError::InvalidUrl { url, reason } => {
+                write!(f, "{} isn't a valid url: {}", url, reason)
+            }
+            DownloadError::Exhausted {
+                url,
+                attempts,
+                last,
+            } => write!(
+                f,
+                "Gave up on {} after {} attempts: {}",
+                url, attempts, last
+            ),
+        }
+    }
+}
+impl std::error::Error for DownloadError {}
@@ -0,0 +1,159 @@
+use crate::{
+    fetch::{Fetcher, Response},
+    retriever::DownloadError,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use reqwest::{
+    header::{HeaderMap, RETRY_AFTER},
+    Url,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{hash_map::RandomState, BTreeMap},
+    hash::{BuildHasher, Hasher},
+    sync::Arc,
+    time::Duration,
+};
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts:  4,
+            base:      Duration::from_millis(500),
+            max_delay: Duration::from_secs(60),
+            jitter:    true,
+        }
+    }
+}
+
+/// How often and how patiently requests to a domain are retried
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub struct RetryPolicy {
+    /// Requests made in total before giving up
+    pub attempts:  usize,
+    /// Wait before the first retry, doubled for every one after it
+    pub base:      Duration,
+    /// Longest wait between two attempts, `Retry-After` included
+    pub max_delay: Duration,
+    /// Waits a random part of the delay so retries don't arrive together
+    pub jitter:    bool,
+}
+
+/// Fetcher retrying transient failures of the one it wraps
+#[derive(Debug, Clone)]
+pub struct Retry {
+    pub inner:    Arc<dyn Fetcher>,
+    pub policies: BTreeMap<String, RetryPolicy>,
+}
+
+impl RetryPolicy {
+    /// Statuses worth asking again for, anything else is final
+    pub fn transient(status: u16) -> bool {
+        matches!(status, 408 | 425 | 429 | 500 | 502 | 503 | 504)
+    }
+
+    /// The policy of the URL's domain in `policies`, or the default one
+    pub fn of(policies: &BTreeMap<String, RetryPolicy>, url: &str) -> Self {
+        url.parse::<Url>()
+            .ok()
+            .and_then(|u| policies.get(u.domain()?).copied())
+            .unwrap_or_default()
+    }
+
+    /// Wait before attempt number `attempt + 1`
+    pub fn delay(&self, attempt: usize) -> Duration {
+        let exp = self
+            .base
+            .saturating_mul(2u32.pow(attempt.saturating_sub(1).min(16) as u32));
+        let delay = exp.min(self.max_delay);
+        match self.jitter {
+            true => delay / 2 + delay.mul_f64(random() / 2.),
+            false => delay,
+        }
+    }
+}
+
+impl Retry {
+    pub fn policy(&self, url: &str) -> RetryPolicy {
+        RetryPolicy::of(&self.policies, url)
+    }
+}
+#[async_trait]
+impl Fetcher for Retry {
+    async fn get(
+        &self, url: &str, headers: HeaderMap,
+    ) -> Result<Response, DownloadError> {
+        let policy = self.policy(url);
+        let mut attempt = 1;
+        loop {
+            let (last, wait) = match self.inner.get(url, headers.clone()).await {
+                Ok(resp) if RetryPolicy::transient(resp.status) => {
+                    let wait = retry_after(&resp.headers);
+                    (resp.error_for_status(url).err().unwrap(), wait)
+                }
+                Ok(resp) => return Ok(resp),
+                Err(e) if e.retryable() => (e, None),
+                Err(e) => return Err(e),
+            };
+            if attempt >= policy.attempts {
+                return Err(DownloadError::Exhausted {
+                    url:      url.to_string(),
+                    attempts: attempt,
+                    last:     Box::new(last),
+                });
+            }
+            let wait =
+                wait.map_or(policy.delay(attempt), |w| w.min(policy.max_delay));
+            tokio::time::sleep(wait).await;
+            attempt += 1;
+        }
+    }
+}
+
+/// `Retry-After` as either a number of seconds or an HTTP date
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    match value.parse::<u64>() {
+        Ok(secs) => Some(Duration::from_secs(secs)),
+        Err(_) => {
+            let at = DateTime::parse_from_rfc2822(value).ok()?;
+            (at.with_timezone(&Utc) - Utc::now()).to_std().ok()
+        }
+    }
+}
+
+/// Between 0 and 1, good enough to spread retries apart
+fn random() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(0);
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[tokio::test]
+async fn retry_backoff() {
+    use crate::fetch::Mock;
+
+    let mock = Mock::default();
+    let url = "https://retry.test/page";
+    mock.page(url, "<html></html>").fail(url, 503, 2);
+    mock.respond("https://retry.test/down", 500, None, b"");
+    let retry = Retry {
+        inner:    Arc::new(mock.clone()),
+        policies: vec![("retry.test".to_string(), RetryPolicy {
+            base: Duration::from_millis(1),
+            ..Default::default()
+        })]
+        .into_iter()
+        .collect(),
+    };
+    assert_eq!(retry.get(url, HeaderMap::new()).await.unwrap().status, 200);
+    assert_eq!(mock.requests().len(), 3);
+    let missing = retry.get("https://retry.test/gone", HeaderMap::new()).await;
+    assert_eq!(missing.unwrap().status, 404);
+    assert_eq!(mock.requests().len(), 4);
+    match retry.get("https://retry.test/down", HeaderMap::new()).await {
+        Err(DownloadError::Exhausted { attempts, .. }) => assert_eq!(attempts, 4),
+        _ => panic!("500 should be retried until the attempts run out"),
+    }
+}
@@ -0,0 +1,148 @@
+use crate::library::BookId;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+impl Default for Parallelism {
+    fn default() -> Self {
+        Self {
+            global:   16,
+            per_host: 4,
+            per_book: 4,
+        }
+    }
+}
+impl Default for Scheduler {
+    fn default() -> Self { Self::new(Parallelism::default()) }
+}
+
+/// How many downloads may run at once
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Parallelism {
+    pub global:   usize,
+    pub per_host: usize,
+    pub per_book: usize,
+}
+
+/// Hands out download slots so that no more than the allowed number of
+/// downloads run in total, against one host or for one book
+#[derive(Debug)]
+pub struct Scheduler {
+    slots: Mutex<Slots>,
+}
+#[derive(Debug)]
+struct Slots {
+    parallelism: Parallelism,
+    global:      Arc<Semaphore>,
+    hosts:       HashMap<String, Arc<Semaphore>>,
+    books:       HashMap<BookId, Arc<Semaphore>>,
+}
+
+/// Permission to download, given back when dropped
+#[derive(Debug)]
+pub struct Slot {
+    _permits: Vec<OwnedSemaphorePermit>,
+}
+
+impl Scheduler {
+    pub fn new(parallelism: Parallelism) -> Self {
+        Self {
+            slots: Mutex::new(Slots::new(parallelism)),
+        }
+    }
+
+    pub fn parallelism(&self) -> Parallelism {
+        self.slots.lock().unwrap().parallelism
+    }
+
+    /// Applies to slots requested from now on, downloads already running
+    /// keep theirs
+    pub fn configure(&self, parallelism: Parallelism) {
+        *self.slots.lock().unwrap() = Slots::new(parallelism);
+    }
+
+    /// Waits for a free slot for downloading `url` for `book`
+    pub async fn slot(&self, book: &BookId, url: &str) -> Slot {
+        let host = url
+            .parse::<Url>()
+            .ok()
+            .and_then(|u| u.host_str().map(|h| h.to_string()))
+            .unwrap_or_default();
+        // Narrowest first, so a busy book doesn't sit on global slots
+        let semaphores = {
+            let mut slots = self.slots.lock().unwrap();
+            let Parallelism {
+                per_host, per_book, ..
+            } = slots.parallelism;
+            vec![
+                slots
+                    .books
+                    .entry(book.clone())
+                    .or_insert_with(|| Arc::new(Semaphore::new(per_book.max(1))))
+                    .clone(),
+                slots
+                    .hosts
+                    .entry(host)
+                    .or_insert_with(|| Arc::new(Semaphore::new(per_host.max(1))))
+                    .clone(),
+                slots.global.clone(),
+            ]
+        };
+        let mut permits = vec![];
+        for semaphore in semaphores {
+            permits.push(
+                semaphore
+                    .acquire_owned()
+                    .await
+                    .expect("The semaphore is never closed"),
+            );
+        }
+        Slot { _permits: permits }
+    }
+}
+
+impl Slots {
+    fn new(parallelism: Parallelism) -> Self {
+        Self {
+            parallelism,
+            global: Arc::new(Semaphore::new(parallelism.global.max(1))),
+            hosts: HashMap::new(),
+            books: HashMap::new(),
+        }
+    }
+}
+
+#[tokio::test]
+async fn schedule_bounds() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let scheduler = Arc::new(Scheduler::new(Parallelism {
+        global:   3,
+        per_host: 8,
+        per_book: 2,
+    }));
+    let (running, most) =
+        (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+    let book = BookId::from("book".to_string());
+    let tasks = (0..6).map(|i| {
+        let (scheduler, running, most) =
+            (scheduler.clone(), running.clone(), most.clone());
+        let book = book.clone();
+        tokio::spawn(async move {
+            let url = format!("https://host{}.test/{}", i % 2, i);
+            let _slot = scheduler.slot(&book, &url).await;
+            most.fetch_max(
+                running.fetch_add(1, Ordering::SeqCst) + 1,
+                Ordering::SeqCst,
+            );
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            running.fetch_sub(1, Ordering::SeqCst);
+        })
+    });
+    futures::future::join_all(tasks).await;
+    assert_eq!(most.load(Ordering::SeqCst), 2);
+}
@@ -1,56 +1,39 @@
-use std::time::Duration;
+use std::{collections::BTreeMap, sync::Arc};
 
-use crate::library::BookName;
-use reqwest::{Client, Url};
+use crate::{
+    cache::{Mode, PageCache, PageRef},
+    config::Config,
+    fetch::{self, Fetcher},
+    library::BookName,
+    retriever::DownloadError,
+    text,
+};
+use once_cell::sync::OnceCell;
+use reqwest::{header::HeaderMap, Url};
 use select::{
     document::Document,
     predicate::{Child, Descendant, Name, Or, Text},
 };
 use serde::{Deserialize, Serialize};
-use tokio::time::Instant;
-
-impl Default for SiteInfo {
-    fn default() -> Self {
-        Self {
-            next: None,
-            time: Instant::now(),
-        }
-    }
-}
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct Source {
     pub location: String,
-    html:         Option<String>,
+    #[serde(default)]
+    pub page:     Option<PageRef>,
+    /// Parsed page, dropped with `release()` once everything was extracted
     #[serde(skip)]
-    doc:          Option<Document>,
+    doc:          OnceCell<Document>,
     #[serde(skip)]
     pub place:    (u16, u16, String),
     #[serde(skip)]
     default:      bool,
-}
-
-#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd)]
-pub struct SiteInfo {
-    pub next: Option<String>,
-    pub time: Instant,
-}
-
-impl SiteInfo {
-    pub fn new() -> Self {
-        Self {
-            next: None,
-            time: Instant::now(),
-        }
-    }
-
-    pub async fn delay(&mut self) {
-        let until = self.time + Duration::from_secs_f32(2.);
-        if until > Instant::now() {
-            tokio::time::sleep_until(until).await;
-        }
-        self.time = until;
-    }
+    /// Where the page comes from, the shared HTTP client when unset
+    #[serde(skip)]
+    fetcher:      Option<Arc<dyn Fetcher>>,
+    /// Sites and cache directory, the default ones when unset
+    #[serde(skip)]
+    config:       Option<Arc<Config>>,
 }
 
 impl Source {
@@ -67,20 +50,73 @@ impl Source {
         }
     }
 
+    /// Fetches the page and keeps it in the page cache, in offline mode it
+    /// only comes from the cache
     #[inline]
     pub async fn download(
-        url: &String, client: Option<&Client>,
-    ) -> (Option<Document>, Option<String>) {
-        let html = client
-            .unwrap_or(&Client::new())
-            .get(url)
-            .send()
-            .await
-            .unwrap()
-            .text()
-            .await
-            .unwrap();
-        (Some(html.clone().as_str().into()), Some(html))
+        url: &String, fetcher: &dyn Fetcher, cache: &PageCache, mode: Mode,
+    ) -> Result<(Document, Option<PageRef>), DownloadError> {
+        if mode == Mode::Offline {
+            let (page, html) = cache
+                .replay(url)
+                .map_err(|_| DownloadError::Offline { url: url.clone() })?;
+            let html = String::from_utf8_lossy(&html).into_owned();
+            return Ok((html.as_str().into(), Some(page)));
+        }
+        let resp = fetcher
+            .get(url, HeaderMap::new())
+            .await?
+            .error_for_status(url)?;
+        let (status, headers) = (resp.status, resp.headers.clone());
+        let html = resp.text().await?;
+        let page = match mode {
+            Mode::Record => {
+                cache.put_response(url, status, &headers, html.as_bytes())
+            }
+            _ => cache.put(url, &html),
+        }
+        .map_err(|e| eprintln!("Couldn't cache {}: {}", url, e))
+        .ok();
+        Ok((html.as_str().into(), page))
+    }
+
+    /// Fetches this and every page reached from it with `fetcher`
+    pub fn with_fetcher(mut self, fetcher: Arc<dyn Fetcher>) -> Self {
+        self.fetcher = Some(fetcher);
+        self
+    }
+
+    fn fetcher(&self) -> Arc<dyn Fetcher> {
+        self.fetcher.clone().unwrap_or_else(fetch::shared)
+    }
+
+    /// Takes its settings and those of every page reached from it from
+    /// `config`
+    pub fn with_config(mut self, config: Arc<Config>) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    fn config(&self) -> Arc<Config> { self.config.clone().unwrap_or_default() }
+
+    fn page_cache(&self) -> PageCache { PageCache::new(&self.config().cache) }
+
+    /// The parsed page, read back from the page cache once if it was
+    /// released
+    fn document(&self) -> Option<&Document> {
+        self.doc
+            .get_or_try_init(|| {
+                let page = self.page.as_ref().ok_or(())?;
+                let html = self.page_cache().get(page).map_err(|_| ())?;
+                Ok::<_, ()>(html.as_str().into())
+            })
+            .ok()
+    }
+
+    /// Frees the parsed page, it's parsed again from the cache when needed
+    pub fn release(&mut self) -> &mut Self {
+        self.doc = OnceCell::new();
+        self
     }
 
     pub async fn fill(&mut self) {
@@ -91,7 +127,7 @@ impl Source {
     }
 
     fn change_place(&self) -> String {
-        let s = if self.check_visual().unwrap() {
+        let s = if self.check_visual().unwrap_or_default() {
             "manga/".to_string()
         } else {
             "novel/".to_string()
@@ -100,70 +136,93 @@ impl Source {
     }
 
     pub async fn refresh_mut(&mut self, url: Option<String>) -> &mut Self {
-        (self.doc, self.html) =
-            Self::download(&url.clone().unwrap_or(self.location.clone()), None)
-                .await;
-        self.place = get_place(&url.unwrap_or(self.location.clone()));
+        let url = url.unwrap_or(self.location.clone());
+        let (fetcher, cache) = (self.fetcher(), self.page_cache());
+        match Self::download(&url, &*fetcher, &cache, self.config().mode).await {
+            Ok((doc, page)) => (self.doc, self.page) = (doc.into(), page),
+            Err(e) => {
+                eprintln!("{}", e);
+                (self.doc, self.page) = (OnceCell::new(), None);
+            }
+        }
+        self.place = get_place(&url).unwrap_or_default();
         self.place.2 = self.change_place();
         self.default = true;
         self
     }
 
+    /// Downloads the page again, an empty source when that fails. Offline
+    /// misses are only reported by try_refresh().
     pub async fn refresh(&self) -> Self {
-        let (doc, html) = Self::download(&self.location, None).await;
-        let mut place = get_place(&self.location);
-        place.2 = self.change_place();
-        Self {
+        self.try_refresh().await.unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            Self {
+                location: self.location.clone(),
+                fetcher: self.fetcher.clone(),
+                config: self.config.clone(),
+                ..Default::default()
+            }
+        })
+    }
+
+    /// Same as refresh() but reports why the page couldn't be had
+    pub async fn try_refresh(&self) -> Result<Self, DownloadError> {
+        let (fetcher, cache) = (self.fetcher(), self.page_cache());
+        let mode = self.config().mode;
+        let (doc, page) =
+            Self::download(&self.location, &*fetcher, &cache, mode).await?;
+        let mut src = Self {
             location: self.location.clone(),
-            doc,
-            html,
-            place,
+            doc: doc.into(),
+            page,
+            place: get_place(&self.location).unwrap_or_default(),
             default: true,
-        }
+            fetcher: self.fetcher.clone(),
+            config: self.config.clone(),
+        };
+        src.place.2 = src.change_place();
+        Ok(src)
     }
 
+    /// Whether the pages are images, `None` when the site isn't known and
+    /// the page wasn't downloaded
     pub fn check_visual(&self) -> Option<bool> {
-        let t = vec!["novel", "royalroad", "comrademao"];
-        let p = vec!["manga", "hentai", "pururin", "luscious"];
-        let f = |s: &&str| -> bool {
-            self.location
-                .parse::<Url>()
-                .unwrap()
-                .origin()
-                .ascii_serialization()
-                .contains(s)
-        };
+        let config = self.config();
+        let (t, p) = (&config.text, &config.visual);
+        let origin = self
+            .location
+            .parse::<Url>()
+            .map(|url| url.origin().ascii_serialization())
+            .unwrap_or_default();
+        let f = |s: &String| -> bool { origin.contains(s) };
         Some(match (t.iter().any(|s| f(s)), p.iter().any(|s| f(s))) {
-            (true, true) => self.text().unwrap().len() < 20,
             (true, false) => false,
             (false, true) => true,
-            (false, false) => self.text().unwrap().len() < 20,
+            _ => {
+                self.document()?;
+                self.text().map_or(true, |t| t.len() < 20)
+            }
         })
     }
 
-    /// Returns something that looks like a book title
+    /// Returns something that looks like a book title, pages without one are
+    /// named after the last part of their url
     pub fn title(&self) -> BookName {
-        println!("{}", self.doc.is_some());
-        let title = self
-            .doc
-            .as_ref()
-            .expect("HTML not found.")
-            .select(Name("title"))
-            .into_selection()
-            .first()
-            .unwrap()
-            .text();
-
-        if title.contains(" Chapter") {
-            title
-                .split(" Chapter")
-                .filter(|&a| a != "")
-                .collect::<Vec<_>>()
-                .first()
-                .unwrap()
-                .to_string()
-        } else {
-            title
+        let title = self.page_title();
+        match title.split(" Chapter").find(|a| !a.trim().is_empty()) {
+            Some(title) => title.trim().to_string(),
+            None => self
+                .location
+                .parse::<Url>()
+                .ok()
+                .and_then(|url| {
+                    let last = url.path_segments()?.rev().find(|s| !s.is_empty());
+                    Some(last.map_or_else(
+                        || url.host_str().unwrap_or_default().to_string(),
+                        |s| s.to_string(),
+                    ))
+                })
+                .unwrap_or_else(|| self.location.clone()),
         }
         .into()
         // .to_ascii_lowercase()
@@ -184,6 +243,13 @@ impl Source {
         // .into()
     }
 
+    /// The page's `<title>` as it is
+    pub fn page_title(&self) -> String {
+        self.document()
+            .and_then(|doc| doc.select(Name("title")).next().map(|t| t.text()))
+            .unwrap_or_default()
+    }
+
     pub fn pos(&self) -> u16 { self.place.1 }
 
     #[allow(dead_code)]
@@ -210,103 +276,172 @@ impl Source {
             })
             .0;
         index.push(&base);
-        index
+        let index: Self = index
             .iter()
             .rev()
             .map(|&a| a)
             .collect::<Vec<_>>()
             .join("/")
-            .into()
+            .into();
+        Self {
+            fetcher: self.fetcher.clone(),
+            config: self.config.clone(),
+            ..index
+        }
     }
 
     /// Returns the biggest congregation of links in the html
     pub async fn chapters(&self) -> Option<Vec<String>> {
-        self.doc.as_ref().map(|a| {
-            a.select(Descendant(
+        self.chapter_links()
+            .map(|links| links.into_iter().map(|(url, _)| url).collect())
+    }
+
+    /// Links of the biggest list on the page along with their text, made
+    /// absolute against the page's location
+    pub fn chapter_links(&self) -> Option<Vec<(String, String)>> {
+        let base = self.location.parse::<Url>().ok();
+        let doc = self.document()?;
+        let links = doc
+            .select(Descendant(
                 Name("div"),
                 Or(Name("p"), Or(Name("table"), Name("ul"))),
             ))
             .map(|a| a.select(Name("a")).into_selection())
-            .max_by(|a, b| a.len().cmp(&b.len()))
-            .unwrap()
+            .max_by(|a, b| a.len().cmp(&b.len()))?
             .iter()
-            .filter_map(|a| a.attr("href"))
-            .map(|a| a.to_string())
-            .collect()
-        })
+            .filter_map(|a| {
+                let href = a.attr("href")?;
+                let url = match &base {
+                    Some(base) => base.join(href).ok()?,
+                    None => href.parse::<Url>().ok()?,
+                };
+                // mailto:, javascript: and the like are never chapters
+                match url.scheme() {
+                    "http" | "https" => {
+                        Some((url.to_string(), a.text().trim().to_string()))
+                    }
+                    _ => None,
+                }
+            })
+            .collect();
+        Some(links)
         /* TODO: Add a similarity check and only return the biggest cluster of similar
         links */
     }
 
+    /// `<meta>` tags of the page that have a name and some content
+    pub fn meta(&self) -> BTreeMap<String, String> {
+        self.document()
+            .map(|doc| {
+                doc.select(Name("meta"))
+                    .filter_map(|m| {
+                        let key = m.attr("name").or(m.attr("property"))?;
+                        let value = m.attr("content")?.trim();
+                        (!value.is_empty())
+                            .then(|| (key.to_string(), value.to_string()))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
     pub async fn next(&self, pred: &str) -> Option<Source> {
-        let s = self.doc.as_ref().and_then(|a| {
+        self.try_next(pred).await.unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            None
+        })
+    }
+
+    /// Same as next() but reports why the next page couldn't be had, `None`
+    /// when there's no link to it
+    pub async fn try_next(
+        &self, pred: &str,
+    ) -> Result<Option<Source>, DownloadError> {
+        let base = self.location.parse::<Url>().ok();
+        let url = self.document().and_then(|a| {
             a.select(Child(Name("a"), Text))
                 .filter(|a| a.text().contains(pred))
-                .map(|a| {
-                    Source::from(
-                        a.parent().unwrap().attr("href").unwrap().to_string(),
-                    )
-                })
+                .filter_map(|a| a.parent()?.attr("href"))
+                .filter_map(|href| base.as_ref()?.join(href).ok())
                 .next()
         });
-        match s {
-            Some(s) => Some(s.refresh().await),
-            None => None,
+        match url {
+            Some(url) => {
+                let src = Self {
+                    fetcher: self.fetcher.clone(),
+                    config: self.config.clone(),
+                    ..Source::from(url.to_string())
+                };
+                src.try_refresh().await.map(Some)
+            }
+            None => Ok(None),
         }
     }
 
     /// Returns the text from the children of the <div> with most <p> tags
     pub fn text(&self) -> Option<Vec<String>> {
-        self.doc.as_ref().map(|a| {
+        self.document().and_then(|a| {
             // TODO: Improve by par_map()?
-            a.select(Child(Name("div"), Name("p")))
-                .map(|a| a.parent().unwrap().children().into_selection())
-                .max_by(|a, b| a.len().cmp(&b.len()))
-                .unwrap()
+            let text = a
+                .select(Child(Name("div"), Name("p")))
+                .filter_map(|a| Some(a.parent()?.children().into_selection()))
+                .max_by(|a, b| a.len().cmp(&b.len()))?
                 .select(Text)
                 .iter()
                 .map(|a| a.text())
-                .collect()
+                .collect();
+            Some(text)
         })
     }
 
+    /// Same as text() but keeps paragraphs, emphasis, images and notes apart
+    pub fn text_document(&self) -> Option<text::Text> {
+        let doc = self.document()?;
+        let mut text = text::Text::parse(doc)?;
+        text.meta.title = self.title().to_string();
+        text.meta.source = self.location.clone();
+        text.meta.chapter = self.place.1;
+        Some(text)
+    }
+
     /// similar to index() return the source addr of the div with most <img>
     pub fn images_batch(&self) -> Option<Vec<String>> {
-        self.doc.as_ref().map(|a| {
-            a.select(Child(Name("div"), Name("img")))
-                .map(|a| a.parent().unwrap().select(Name("img")).into_selection())
-                .max_by(|a, b| a.len().cmp(&b.len()))
-                .unwrap()
+        self.document().and_then(|a| {
+            let images = a
+                .select(Child(Name("div"), Name("img")))
+                .filter_map(|a| {
+                    Some(a.parent()?.select(Name("img")).into_selection())
+                })
+                .max_by(|a, b| a.len().cmp(&b.len()))?
                 .iter()
-                .map(|a| a.attr("src").unwrap().to_string())
-                .collect()
+                .filter_map(|a| Some(a.attr("src")?.to_string()))
+                .collect();
+            Some(images)
         })
         /* TODO: Similar to index() add a check for links similarity */
     }
 
     //TODO: Levarage the power of next() to get the whole chapter
     pub async fn images_single(&self) -> Vec<String> {
-        match &self.doc {
+        match self.doc.get() {
             Some(_d) => vec![],
             None => vec![],
         }
     }
 
-    pub fn domain(&self) -> String {
-        self.location
-            .parse::<Url>()
-            .unwrap()
-            .domain()
-            .unwrap()
-            .to_string()
+    /// Host of the location, `None` when it doesn't parse or has none
+    pub fn domain(&self) -> Option<String> {
+        let url = self.location.parse::<Url>().ok()?;
+        url.host_str().map(str::to_string)
     }
 }
 
-pub fn get_place(url: &String) -> (u16, u16, String) {
-    let url = url.parse::<Url>().expect("Not a Url string.");
+/// Volume, chapter and name of the book found in the path of the URL, `None`
+/// when it isn't a URL with a path
+pub fn get_place(url: &String) -> Option<(u16, u16, String)> {
+    let url = url.parse::<Url>().ok()?;
     let segments = url
-        .path_segments()
-        .unwrap()
+        .path_segments()?
         .rev()
         .filter(|&a| a != "")
         .collect::<Vec<_>>();
@@ -326,18 +461,20 @@ pub fn get_place(url: &String) -> (u16, u16, String) {
     } else {
         segments.iter().rev().skip(1).next()
     };
-    match (numbers.as_slice(), index_candidate) {
+    Some(match (numbers.as_slice(), index_candidate) {
         ([x @ 0..=9000, y @ 0..=9000, ..], Some(&z)) => (*x, *y, z.to_string()),
         ([x @ 0..=9000], Some(z)) => (0, *x, z.to_string()),
         ([], Some(z)) => (0, 0, z.to_string()),
         _ => (0, 0, "".to_string()),
-    }
+    })
 }
 
 impl Eq for Source {}
 impl PartialEq for Source {
     fn eq(&self, other: &Self) -> bool {
-        self.location == other.location && self.html == other.html
+        self.location == other.location &&
+            self.page.as_ref().map(|p| &p.sha256) ==
+                other.page.as_ref().map(|p| &p.sha256)
     }
 }
 impl Ord for Source {
@@ -353,26 +490,30 @@ impl PartialOrd for Source {
 impl From<String> for Source {
     fn from(url: String) -> Self {
         url.parse::<Url>().expect("Couldn't parse.");
-        let place = get_place(&url);
+        let place = get_place(&url).unwrap_or_default();
         Self {
             location: url.clone(),
-            html: None,
-            doc: None,
+            page: None,
+            doc: OnceCell::new(),
             place,
             default: false,
+            fetcher: None,
+            config: None,
         }
     }
 }
 impl From<&String> for Source {
     fn from(url: &String) -> Self {
         url.parse::<Url>().expect("Couldn't parse.");
-        let place = get_place(&url);
+        let place = get_place(&url).unwrap_or_default();
         Self {
             location: url.clone(),
-            html: None,
-            doc: None,
+            page: None,
+            doc: OnceCell::new(),
             place,
             default: false,
+            fetcher: None,
+            config: None,
         }
     }
 }
@@ -0,0 +1,391 @@
+use crate::{
+    library::{Book, BookId, Chapter, Content, Library},
+    source::Source,
+    storage::{Json, Query, Storage},
+};
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, OptionalExtension, Row, Transaction};
+use std::{
+    collections::BTreeMap,
+    io,
+    path::{Path, PathBuf},
+};
+
+/// Bumped through `PRAGMA user_version` whenever the tables change
+const VERSION: u32 = 1;
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS books (
+    id      TEXT PRIMARY KEY,
+    name    TEXT NOT NULL,
+    aliases TEXT NOT NULL,
+    url     TEXT NOT NULL,
+    visual  INTEGER,
+    status  TEXT NOT NULL,
+    updated TEXT,
+    filter  TEXT
+);
+CREATE INDEX IF NOT EXISTS books_name ON books (name);
+CREATE INDEX IF NOT EXISTS books_status ON books (status);
+CREATE INDEX IF NOT EXISTS books_updated ON books (updated);
+CREATE TABLE IF NOT EXISTS chapters (
+    book TEXT NOT NULL REFERENCES books (id) ON DELETE CASCADE,
+    num  INTEGER NOT NULL,
+    url  TEXT NOT NULL,
+    pos  INTEGER NOT NULL,
+    PRIMARY KEY (book, num)
+);
+CREATE TABLE IF NOT EXISTS contents (
+    book    TEXT NOT NULL,
+    chapter INTEGER NOT NULL,
+    num     INTEGER NOT NULL,
+    path    TEXT NOT NULL,
+    url     TEXT NOT NULL,
+    referer TEXT,
+    sha256  TEXT,
+    mime    TEXT,
+    size    INTEGER NOT NULL,
+    width   INTEGER,
+    height  INTEGER,
+    words   INTEGER,
+    fetched TEXT,
+    status  TEXT NOT NULL,
+    PRIMARY KEY (book, chapter, num),
+    FOREIGN KEY (book, chapter) REFERENCES chapters (book, num) ON DELETE CASCADE
+);
+CREATE TABLE IF NOT EXISTS metadata (
+    book  TEXT NOT NULL REFERENCES books (id) ON DELETE CASCADE,
+    key   TEXT NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY (book, key)
+);
+CREATE TABLE IF NOT EXISTS progress (
+    book    TEXT PRIMARY KEY REFERENCES books (id) ON DELETE CASCADE,
+    chapter INTEGER NOT NULL
+);
+";
+
+/// Library kept in an SQLite database, one row per book, chapter and page
+pub struct Sqlite {
+    conn:     Connection,
+    location: PathBuf,
+}
+
+impl Sqlite {
+    pub fn open(location: &Path) -> io::Result<Self> {
+        location.parent().map(std::fs::create_dir_all).transpose()?;
+        let conn = Connection::open(location).map_err(sql)?;
+        conn.execute_batch(SCHEMA).map_err(sql)?;
+        conn.execute_batch(&format!("PRAGMA user_version = {};", VERSION))
+            .map_err(sql)?;
+        conn.execute_batch("PRAGMA foreign_keys = ON;")
+            .map_err(sql)?;
+        Ok(Self {
+            conn,
+            location: location.to_path_buf(),
+        })
+    }
+
+    /// Copies a JSON library into the database, returning how many books it
+    /// had
+    pub fn import(&mut self, json: &Path) -> io::Result<usize> {
+        let lib = Json::new(json).load()?;
+        self.save(&lib)?;
+        Ok(lib.books.len())
+    }
+
+    /// Replaces everything stored about the book. Pages change without the
+    /// book's `updated` moving, so its chapters are always written again.
+    fn write_book(tx: &Transaction, book: &Book) -> rusqlite::Result<()> {
+        let id: &str = &book.id;
+        let updated = book.updated.map(|u| u.to_rfc3339());
+        tx.execute(
+            "INSERT INTO books (id, name, aliases, url, visual, status, updated,
+                filter)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+             ON CONFLICT (id) DO UPDATE SET name = ?2, aliases = ?3,
+                url = ?4, visual = ?5, status = ?6, updated = ?7, filter = ?8",
+            params![
+                id,
+                *book.name,
+                serde_json::to_string(&book.aliases).unwrap(),
+                book.index.location,
+                book.visual(),
+                book.status.as_str(),
+                updated,
+                (!book.filter.is_empty())
+                    .then(|| serde_json::to_string(&book.filter).unwrap())
+            ],
+        )?;
+        tx.execute(
+            "INSERT OR REPLACE INTO progress (book, chapter) VALUES (?, ?)",
+            params![id, book.pos],
+        )?;
+        tx.execute("DELETE FROM metadata WHERE book = ?", params![id])?;
+        for (key, value) in &book.meta {
+            tx.execute(
+                "INSERT INTO metadata (book, key, value) VALUES (?, ?, ?)",
+                params![id, key, value],
+            )?;
+        }
+        tx.execute("DELETE FROM chapters WHERE book = ?", params![id])?;
+        for (num, ch) in book.chapters() {
+            tx.execute(
+                "INSERT INTO chapters (book, num, url, pos) VALUES (?, ?, ?, ?)",
+                params![id, num, ch.page.location, ch.pos],
+            )?;
+            for cnt in ch.pages().values() {
+                tx.execute(
+                    "INSERT INTO contents (book, chapter, num, path, url, referer,
+                        sha256, mime, size, width, height, words, fetched, status)
+                     VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                    params![
+                        id,
+                        num,
+                        cnt.num,
+                        cnt.path.to_string_lossy(),
+                        cnt.url,
+                        cnt.referer,
+                        cnt.sha256,
+                        cnt.mime,
+                        cnt.size as i64,
+                        cnt.dimensions.map(|d| d.0),
+                        cnt.dimensions.map(|d| d.1),
+                        cnt.words.map(|w| w as i64),
+                        cnt.fetched.map(|f| f.to_rfc3339()),
+                        serde_json::to_string(&cnt.status).unwrap(),
+                    ],
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_book(&self, row: &Row) -> rusqlite::Result<Book> {
+        let id: String = row.get("id")?;
+        let mut book = Book::new(
+            row.get::<_, String>("name")?.into(),
+            source(row.get("url")?),
+        );
+        book.id = id.clone().into();
+        book.aliases = serde_json::from_str(&row.get::<_, String>("aliases")?)
+            .unwrap_or_default();
+        book.visual = row.get("visual")?;
+        book.status = row.get::<_, String>("status")?.parse().unwrap_or_default();
+        book.updated = row.get::<_, Option<String>>("updated")?.and_then(time);
+        book.filter = row
+            .get::<_, Option<String>>("filter")?
+            .and_then(|f| serde_json::from_str(&f).ok())
+            .unwrap_or_default();
+        book.pos = self
+            .conn
+            .query_row(
+                "SELECT chapter FROM progress WHERE book = ?",
+                params![id],
+                |r| r.get(0),
+            )
+            .optional()?
+            .unwrap_or_default();
+        let mut stmt = self
+            .conn
+            .prepare("SELECT key, value FROM metadata WHERE book = ?")?;
+        book.meta = stmt
+            .query_map(params![id], |r| Ok((r.get(0)?, r.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        let mut stmt = self
+            .conn
+            .prepare("SELECT num, url, pos FROM chapters WHERE book = ?")?;
+        let chapters = stmt
+            .query_map(params![id], |r| {
+                let ch = Chapter {
+                    page: source(r.get(1)?),
+                    pos: r.get(2)?,
+                    ..Default::default()
+                };
+                Ok((r.get::<_, u16>(0)?, ch))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        let mut stmt = self
+            .conn
+            .prepare("SELECT * FROM contents WHERE book = ? AND chapter = ?")?;
+        for (num, mut ch) in chapters {
+            ch.content = stmt
+                .query_map(params![id, num], |r| {
+                    let cnt = Content {
+                        num:        r.get("num")?,
+                        path:       PathBuf::from(r.get::<_, String>("path")?),
+                        url:        r.get("url")?,
+                        referer:    r.get("referer")?,
+                        sha256:     r.get("sha256")?,
+                        mime:       r.get("mime")?,
+                        size:       r.get::<_, i64>("size")? as u64,
+                        dimensions: r
+                            .get::<_, Option<u32>>("width")?
+                            .zip(r.get::<_, Option<u32>>("height")?),
+                        words:      r
+                            .get::<_, Option<i64>>("words")?
+                            .map(|w| w as usize),
+                        fetched:    r
+                            .get::<_, Option<String>>("fetched")?
+                            .and_then(time),
+                        status:     serde_json::from_str(
+                            &r.get::<_, String>("status")?,
+                        )
+                        .unwrap_or_default(),
+                    };
+                    Ok((cnt.num, cnt))
+                })?
+                .collect::<rusqlite::Result<BTreeMap<_, _>>>()?;
+            book.chapters.insert(num, ch);
+        }
+        Ok(book)
+    }
+}
+impl Storage for Sqlite {
+    fn load(&self) -> io::Result<Library> {
+        let mut stmt = self.conn.prepare("SELECT * FROM books").map_err(sql)?;
+        let books = stmt
+            .query_map(params![], |row| self.read_book(row))
+            .map_err(sql)?
+            .map(|b| b.map(|b| (b.id.clone(), b)))
+            .collect::<rusqlite::Result<_>>()
+            .map_err(sql)?;
+        Ok(Library {
+            books,
+            location: self.location.clone(),
+            ..Default::default()
+        })
+    }
+
+    fn save(&mut self, lib: &Library) -> io::Result<()> {
+        let tx = self.conn.transaction().map_err(sql)?;
+        let stored = tx
+            .prepare("SELECT id FROM books")
+            .and_then(|mut s| {
+                s.query_map(params![], |r| r.get::<_, String>(0))?.collect()
+            })
+            .map_err(sql)?;
+        let stored: Vec<String> = stored;
+        for id in stored
+            .into_iter()
+            .filter(|id| !lib.books.contains_key(&id.clone().into()))
+        {
+            tx.execute("DELETE FROM books WHERE id = ?", params![id])
+                .map_err(sql)?;
+        }
+        for book in lib.books.values() {
+            Self::write_book(&tx, book).map_err(sql)?;
+        }
+        tx.commit().map_err(sql)
+    }
+
+    fn save_book(&mut self, book: &Book) -> io::Result<()> {
+        let tx = self.conn.transaction().map_err(sql)?;
+        Self::write_book(&tx, book).map_err(sql)?;
+        tx.commit().map_err(sql)
+    }
+
+    fn remove_book(&mut self, id: &BookId) -> io::Result<()> {
+        let id: &str = id;
+        self.conn
+            .execute("DELETE FROM books WHERE id = ?", params![id])
+            .map(|_| ())
+            .map_err(sql)
+    }
+
+    fn find(&self, query: &Query) -> io::Result<Vec<BookId>> {
+        let mut sql_query = "SELECT id FROM books WHERE 1 = 1".to_string();
+        let mut args = vec![];
+        if let Some(name) = &query.name {
+            sql_query += " AND (name LIKE '%' || ?1 || '%' OR aliases LIKE '%' || ?1 || '%')";
+            args.push(name.clone());
+        }
+        if let Some(status) = query.status {
+            sql_query += " AND status = ?";
+            args.push(status.as_str().to_string());
+        }
+        if let Some(since) = query.updated_since {
+            sql_query += " AND updated >= ?";
+            args.push(since.to_rfc3339());
+        }
+        let mut stmt = self.conn.prepare(&sql_query).map_err(sql)?;
+        let ids = stmt
+            .query_map(args, |r| r.get::<_, String>(0))
+            .map_err(sql)?
+            .map(|id| id.map(BookId::from))
+            .collect::<rusqlite::Result<_>>()
+            .map_err(sql)?;
+        Ok(ids)
+    }
+}
+
+fn sql(e: rusqlite::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+fn time(s: String) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(&s)
+        .ok()
+        .map(|t| t.with_timezone(&Utc))
+}
+
+fn source(url: String) -> Source {
+    match url.is_empty() {
+        true => Source::default(),
+        false => Source::from(url),
+    }
+}
+
+#[test]
+fn sqlite_roundtrip() {
+    use crate::library::BookStatus;
+
+    let dir = std::env::temp_dir()
+        .join(format!("ehound-sqlite-{}", std::process::id()));
+    std::fs::remove_dir_all(&dir).ok();
+    let mut book = Book::from("Some Book".to_string());
+    book.status = BookStatus::Ongoing;
+    book.updated = Some(Utc::now());
+    book.meta
+        .insert("author".to_string(), "Someone".to_string());
+    book.filter.range = Some(crate::filter::Range::From(2));
+    let mut ch = Chapter::default();
+    ch.page = "https://example.com/some-book/chapter-2".to_string().into();
+    let mut cnt = Content::new(0, "https://example.com/0.png".to_string());
+    cnt.dimensions = Some((16, 32));
+    ch.add_content(cnt);
+    book.chapters.insert(ch.num(), ch);
+    let mut lib = Library::default();
+    lib.insert(book.clone());
+
+    let mut db = Sqlite::open(&dir.join("library.db")).unwrap();
+    db.save(&lib).unwrap();
+    let loaded = db.load().unwrap();
+    let stored = &loaded.books[&book.id];
+    assert_eq!(stored.meta, book.meta);
+    assert_eq!(stored.filter, book.filter);
+    let ch = stored.chapters().values().next().unwrap();
+    assert_eq!(ch.pages()[&0].dimensions, Some((16, 32)));
+
+    // A page added later is written even though the book's time is the same
+    let page = Content::new(1, "https://example.com/1.png".to_string());
+    let book_mut = lib.books.get_mut(&book.id).unwrap();
+    book_mut
+        .chapters
+        .values_mut()
+        .next()
+        .unwrap()
+        .add_content(page);
+    db.save_book(&lib.books[&book.id]).unwrap();
+    let loaded = db.load().unwrap();
+    let ch = loaded.books[&book.id].chapters().values().next().unwrap();
+    assert_eq!(ch.pages().len(), 2);
+    let query = Query {
+        name: Some("some".to_string()),
+        status: Some(BookStatus::Ongoing),
+        ..Default::default()
+    };
+    assert_eq!(db.find(&query).unwrap(), vec![book.id.clone()]);
+    db.remove_book(&book.id).unwrap();
+    assert!(db.load().unwrap().books.is_empty());
+    std::fs::remove_dir_all(dir).ok();
+}
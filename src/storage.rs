@@ -0,0 +1,197 @@
+use crate::{
+    library::{Book, BookId, BookStatus, Library},
+    migrate,
+    sqlite::Sqlite,
+};
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+/// Somewhere a library can be kept between runs
+pub trait Storage {
+    fn load(&self) -> io::Result<Library>;
+
+    fn save(&mut self, lib: &Library) -> io::Result<()>;
+
+    /// Writes a single book without touching the rest of the library
+    fn save_book(&mut self, book: &Book) -> io::Result<()>;
+
+    fn remove_book(&mut self, id: &BookId) -> io::Result<()>;
+
+    fn find(&self, query: &Query) -> io::Result<Vec<BookId>>;
+}
+
+/// The storage of a library, opened on first use and kept open while the
+/// library stays where it is. Clones share it.
+#[derive(Default, Clone)]
+pub struct Handle(Arc<Mutex<Option<Opened>>>);
+type Opened = (PathBuf, Box<dyn Storage + Send>);
+
+/// Books matching all of the given conditions
+#[derive(Default, Debug, Clone)]
+pub struct Query {
+    /// Case insensitive part of the name or a former name
+    pub name:          Option<String>,
+    pub status:        Option<BookStatus>,
+    pub updated_since: Option<DateTime<Utc>>,
+}
+
+/// The whole library as one JSON file, replaced atomically on every save
+#[derive(Debug, Clone)]
+pub struct Json {
+    pub location: PathBuf,
+    /// Whether the file on disk was brought up to the current version
+    migrated:     bool,
+}
+
+/// Picks the backend from the extension, `.db`/`.sqlite` files are SQLite
+/// databases and everything else is JSON
+pub fn open(location: &Path) -> io::Result<Box<dyn Storage + Send>> {
+    match location.extension().and_then(|e| e.to_str()) {
+        Some("db") | Some("sqlite") | Some("sqlite3") => {
+            Ok(Box::new(Sqlite::open(location)?))
+        }
+        _ => Ok(Box::new(Json::new(location))),
+    }
+}
+
+impl Handle {
+    /// Runs `f` on the storage at `location`, opening it first unless it's
+    /// the one already open
+    pub fn with<T>(
+        &self, location: &Path, f: impl FnOnce(&mut dyn Storage) -> io::Result<T>,
+    ) -> io::Result<T> {
+        let mut opened = self.0.lock().unwrap();
+        if opened.as_ref().map_or(true, |(at, _)| at != location) {
+            *opened = Some((location.to_path_buf(), open(location)?));
+        }
+        let (_, storage) = opened.as_mut().expect("Opened above");
+        f(storage.as_mut())
+    }
+}
+impl fmt::Debug for Handle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let opened = self.0.lock().unwrap();
+        f.debug_tuple("Handle")
+            .field(&opened.as_ref().map(|(at, _)| at))
+            .finish()
+    }
+}
+
+impl Query {
+    pub fn matches(&self, book: &Book) -> bool {
+        self.name.as_ref().map_or(true, |n| {
+            std::iter::once(&book.name)
+                .chain(&book.aliases)
+                .any(|a| a.to_lowercase().contains(&n.to_lowercase()))
+        }) && self.status.map_or(true, |s| book.status == s) &&
+            self.updated_since
+                .map_or(true, |t| book.updated.map_or(false, |u| u >= t))
+    }
+}
+
+impl Json {
+    pub fn new(location: &Path) -> Self {
+        Self {
+            location: location.to_path_buf(),
+            migrated: false,
+        }
+    }
+
+    /// Upgrades older files in memory only, their files are moved once the
+    /// library is migrated on disk
+    fn read(path: &Path) -> io::Result<Library> {
+        let mut value =
+            serde_json::from_reader(BufReader::new(File::open(path)?))?;
+        migrate::LIBRARY.upgrade(&mut value)?;
+        Ok(serde_json::from_value(value)?)
+    }
+
+    fn load_or_default(&self) -> io::Result<Library> {
+        match self.load() {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Library {
+                location: self.location.clone(),
+                ..Default::default()
+            }),
+            res => res,
+        }
+    }
+}
+impl Storage for Json {
+    /// Reads the library, upgrading older formats and falling back to the
+    /// backup when the file is unreadable
+    fn load(&self) -> io::Result<Library> {
+        let mut lib = Self::read(&self.location).or_else(|e| match e.kind() {
+            io::ErrorKind::NotFound => Err(e),
+            _ => Self::read(&self.location.with_extension("json.bak"))
+                .map_err(|_| e),
+        })?;
+        lib.location = self.location.clone();
+        Ok(lib)
+    }
+
+    /// Writes next to the location and renames into place, keeping the
+    /// previous file as a backup. A file of an older version is migrated
+    /// first, so its files are moved before it's replaced.
+    fn save(&mut self, lib: &Library) -> io::Result<()> {
+        if !self.migrated {
+            // An unreadable file is replaced, what was loaded came from the
+            // backup
+            let old = File::open(&self.location).ok().and_then(|file| {
+                serde_json::from_reader::<_, Value>(BufReader::new(file)).ok()
+            });
+            let current = migrate::LIBRARY.current();
+            if old.map_or(false, |v| migrate::version(&v) < current) {
+                migrate::LIBRARY.migrate_file(&self.location, false)?;
+            }
+            self.migrated = true;
+        }
+        let tmp = self.location.with_extension("json.tmp");
+        self.location
+            .parent()
+            .map(std::fs::create_dir_all)
+            .transpose()?;
+        let mut file = File::with_options()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp)?;
+        serde_json::to_writer(&mut file, lib)?;
+        file.sync_all()?;
+        if self.location.exists() {
+            std::fs::copy(
+                &self.location,
+                self.location.with_extension("json.bak"),
+            )?;
+        }
+        std::fs::rename(&tmp, &self.location)
+    }
+
+    fn save_book(&mut self, book: &Book) -> io::Result<()> {
+        let mut lib = self.load_or_default()?;
+        lib.books.insert(book.id.clone(), book.clone());
+        self.save(&lib)
+    }
+
+    fn remove_book(&mut self, id: &BookId) -> io::Result<()> {
+        let mut lib = self.load_or_default()?;
+        lib.books.remove(id);
+        self.save(&lib)
+    }
+
+    fn find(&self, query: &Query) -> io::Result<Vec<BookId>> {
+        Ok(self
+            .load_or_default()?
+            .books
+            .values()
+            .filter(|b| query.matches(b))
+            .map(|b| b.id.clone())
+            .collect())
+    }
+}
@@ -0,0 +1,148 @@
+use select::{document::Document, node::Node, predicate::Name};
+use serde::{Deserialize, Serialize};
+
+/// A chapter of prose stored as structured blocks instead of a flat string
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Text {
+    pub meta:   TextMeta,
+    pub blocks: Vec<Block>,
+}
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TextMeta {
+    pub title:   String,
+    pub source:  String,
+    pub chapter: u16,
+    pub words:   usize,
+}
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "content", rename_all = "lowercase")]
+pub enum Block {
+    Heading(Vec<Span>),
+    Paragraph(Vec<Span>),
+    Note(Vec<Span>),
+    Image { src: String, alt: Option<String> },
+    Break,
+}
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "text", rename_all = "lowercase")]
+pub enum Span {
+    Plain(String),
+    Emphasis(String),
+    Strong(String),
+}
+
+impl Text {
+    /// Builds the document from the children of the <div> with most <p> tags
+    pub fn parse(doc: &Document) -> Option<Self> {
+        let body = doc
+            .select(Name("p"))
+            .filter_map(|p| p.parent())
+            .filter(|d| d.is(Name("div")))
+            .max_by_key(|d| d.children().filter(|c| c.is(Name("p"))).count())?;
+        let blocks = body
+            .children()
+            .filter_map(|n| Block::from_node(&n))
+            .collect::<Vec<_>>();
+        let mut text = Self {
+            blocks,
+            ..Default::default()
+        };
+        text.meta.words = text.words();
+        Some(text)
+    }
+
+    pub fn words(&self) -> usize {
+        self.blocks
+            .iter()
+            .map(|b| b.spans().iter().map(Span::text).collect::<String>())
+            .map(|s| s.split_whitespace().count())
+            .sum()
+    }
+
+    /// Flattens the document back into plain paragraphs
+    pub fn plain(&self) -> String {
+        self.blocks
+            .iter()
+            .filter(|b| !b.spans().is_empty())
+            .map(|b| b.spans().iter().map(Span::text).collect::<String>())
+            .collect::<Vec<_>>()
+            .join("\n\n")
+    }
+}
+impl Block {
+    fn from_node(node: &Node) -> Option<Self> {
+        let spans = || Span::collect(node);
+        let class = node.attr("class").unwrap_or_default().to_lowercase();
+        match node.name() {
+            Some("p") | Some("div") if class.contains("note") => {
+                Some(Block::Note(spans()))
+            }
+            Some("blockquote") | Some("aside") => Some(Block::Note(spans())),
+            Some("h1") | Some("h2") | Some("h3") | Some("h4") => {
+                Some(Block::Heading(spans()))
+            }
+            Some("p") => {
+                match node.select(Name("img")).next() {
+                    Some(img) if node.text().trim().is_empty() => {
+                        Self::from_node(&img)
+                    }
+                    _ => Some(Block::Paragraph(spans())),
+                }
+            }
+            Some("img") => Some(Block::Image {
+                src: node.attr("src")?.to_string(),
+                alt: node.attr("alt").map(|a| a.to_string()),
+            }),
+            Some("hr") => Some(Block::Break),
+            _ => None,
+        }
+        .filter(|b| !matches!(b, Block::Paragraph(s) | Block::Note(s) if s.is_empty()))
+    }
+
+    pub fn spans(&self) -> &[Span] {
+        match self {
+            Block::Heading(s) | Block::Paragraph(s) | Block::Note(s) => s,
+            _ => &[],
+        }
+    }
+}
+impl Span {
+    fn collect(node: &Node) -> Vec<Span> {
+        let mut spans = vec![];
+        node.children().for_each(|n| match (n.name(), n.as_text()) {
+            (_, Some(t)) => spans.push(Span::Plain(t.to_string())),
+            (Some("em"), _) | (Some("i"), _) => {
+                spans.push(Span::Emphasis(n.text()))
+            }
+            (Some("strong"), _) | (Some("b"), _) => {
+                spans.push(Span::Strong(n.text()))
+            }
+            (Some("br"), _) => spans.push(Span::Plain("\n".to_string())),
+            _ => spans.extend(Self::collect(&n)),
+        });
+        spans.retain(|s| !s.text().is_empty());
+        spans
+    }
+
+    pub fn text(&self) -> &str {
+        match self {
+            Span::Plain(s) | Span::Emphasis(s) | Span::Strong(s) => s,
+        }
+    }
+}
+
+#[test]
+fn text_parse() {
+    let html = "<html><body><div><p>It was <em>late</em>.</p><p>Then \
+                <b>dawn</b>.</p><blockquote>TL note</blockquote><p><img \
+                src=\"a.png\"></p></div></body></html>";
+    let text = Text::parse(&Document::from(html)).unwrap();
+    assert_eq!(text.blocks.len(), 4);
+    assert_eq!(
+        text.blocks[0].spans()[1],
+        Span::Emphasis("late".to_string())
+    );
+    assert!(matches!(text.blocks[2], Block::Note(_)));
+    assert!(matches!(text.blocks[3], Block::Image { .. }));
+    assert_eq!(text.words(), 7);
+}
@@ -1,108 +1,1216 @@
 use crate::{
-    library::{Book, BookName, Library},
-    retriever::Retriever,
-    source::{SiteInfo, Source},
+    config::Config,
+    events::Event,
+    fetch::Fetcher,
+    filter::{ChapterFilter, Range},
+    library::{Book, BookId, BookName, BookStatus, Content, Library},
+    limit::Limits,
+    notify::{Notifier, Release},
+    queue::{Job, JobState, Priority, Queue, Task},
+    retriever::{ChapterDownload, DownloadError, Retriever},
+    schedule::Parallelism,
+    source::{get_place, Source},
+    verify::Report,
+    watch::Watch,
 };
-use futures::future::join_all;
+use chrono::Utc;
+use futures::{stream::FuturesUnordered, StreamExt};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::{BTreeMap, HashMap},
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    future::Future,
+    io,
     sync::Arc,
+    time::{Duration, Instant},
 };
-use tokio::sync::Mutex;
+use tokio::sync::{broadcast::Receiver, Mutex};
 
 #[derive(Default, Clone, Debug)]
 pub struct Manager {
-    dl:    Retriever,
-    lib:   Library,
-    sites: Arc<Mutex<BTreeMap<String, SiteInfo>>>,
-    preds: HashMap<String, String>,
+    dl:       Retriever,
+    lib:      Library,
+    queue:    Arc<Mutex<Queue>>,
+    watch:    Arc<Mutex<Watch>>,
+    notifier: Arc<Mutex<Notifier>>,
+}
+
+/// What a refresh found, book by book
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct RefreshReport {
+    pub books:   BTreeMap<BookId, BookRefresh>,
+    pub elapsed: Duration,
+}
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct BookRefresh {
+    /// Chapters downloaded in full
+    pub new:      Vec<u16>,
+    /// Chapters with pages that couldn't be downloaded, and why
+    pub failed:   BTreeMap<u16, String>,
+    /// Chapters found but left out by the book's filter
+    #[serde(default)]
+    pub filtered: Vec<u16>,
+    /// Why the book wasn't looked at, if it wasn't
+    pub skipped:  Option<String>,
+    /// Why the walk through the chapters ended early, if it did
+    #[serde(default)]
+    pub stopped:  Option<String>,
+}
+
+/// Whether adding a book downloads its chapters or only keeps track of it
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub enum AddMode {
+    Download,
+    Track,
+}
+/// What became of a book after adding it
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Kept {
+    /// Every chapter let through is downloaded, or only tracking was asked
+    Complete,
+    /// Kept and marked incomplete, see `failed`
+    Partial,
+    /// None of the chapters of a new book could be had, so it was taken out
+    /// again along with its files
+    RolledBack,
+}
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub enum BookKind {
+    Visual,
+    Text,
+    Unknown,
+}
+/// What adding a book found and downloaded
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct AddBookReport {
+    pub book:       BookId,
+    pub name:       BookName,
+    pub kind:       BookKind,
+    /// Chapters listed in the book's index
+    pub found:      usize,
+    /// Chapters left out by the book's filter
+    pub skipped:    usize,
+    /// Chapters downloaded in full, or already complete
+    pub downloaded: usize,
+    /// Chapters that couldn't be downloaded in full, and why
+    pub failed:     BTreeMap<String, String>,
+    /// Whether the book was kept whole, kept incomplete or taken out again
+    #[serde(default)]
+    pub kept:       Kept,
+}
+
+/// What adding a URL would bring, worked out from its index alone
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct Preview {
+    pub url:        String,
+    pub index:      String,
+    pub title:      BookName,
+    pub kind:       BookKind,
+    pub chapters:   usize,
+    /// Lowest and highest chapter numbers found in the links
+    pub range:      Option<(u16, u16)>,
+    pub first:      Option<String>,
+    pub last:       Option<String>,
+    pub meta:       BTreeMap<String, String>,
+    /// Share of the index links that carry a chapter number of their own,
+    /// low values mean the chapter list was probably not found
+    pub confidence: f32,
+}
+
+/// What a finished job brought back
+enum Outcome {
+    Chapters(Vec<String>),
+    Chapter(ChapterDownload),
+    Page(u16, Content),
+}
+impl Default for Kept {
+    fn default() -> Self { Kept::Complete }
+}
+impl Default for BookKind {
+    fn default() -> Self { BookKind::Unknown }
+}
+impl From<Option<bool>> for BookKind {
+    fn from(visual: Option<bool>) -> Self {
+        match visual {
+            Some(true) => BookKind::Visual,
+            Some(false) => BookKind::Text,
+            None => BookKind::Unknown,
+        }
+    }
 }
 impl Manager {
-    pub async fn add_book(&mut self, bookname: Option<BookName>, source: Source) {
-        let mut src = source.refresh().await;
-        let bn = bookname.clone().unwrap_or(src.title()).to_string();
-        self.sites.lock().await.insert(bn.clone(), SiteInfo::new());
-        src = src.index().await.refresh().await;
-        let mut book = Book::default();
-        (book.name, book.index, book.pos) = (bn.into(), src.clone(), src.pos());
+    /// Longest the daemon sleeps before looking at the schedule again, so
+    /// books added meanwhile are picked up
+    const IDLE: Duration = Duration::from_secs(60);
+    /// Meta key of books that are missing chapters since being added
+    pub const INCOMPLETE: &'static str = "incomplete";
+    /// Meta key of the furthest chapter a refresh walked past without
+    /// downloading it because the filter left it out
+    pub const WALKED: &'static str = "walked";
+
+    /// Manager over the library `config` points to, or an empty one if
+    /// nothing has been saved there yet. Everything it does goes by the
+    /// config, other managers keep their own.
+    pub async fn open(config: Config) -> io::Result<Self> {
+        let location = config.library();
+        let cache = config.cache.clone();
+        let lib = match Library::load(location.clone()).await {
+            Ok(lib) => lib,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Library {
+                location,
+                ..Default::default()
+            },
+            Err(e) => return Err(e),
+        };
+        let queue = Queue::open(cache.join("queue.json"))?;
+        let mut watch = Watch::open(cache.join("watch.json"))?;
+        watch.intervals = config.intervals;
+        let mut notifier = Notifier::open(cache.join("notify.json"))?;
+        notifier.global = config.notify.clone();
+        Ok(Self {
+            dl: Retriever::new(Arc::new(config)),
+            lib,
+            queue: Arc::new(Mutex::new(queue)),
+            watch: Arc::new(Mutex::new(watch)),
+            notifier: Arc::new(Mutex::new(notifier)),
+            ..Default::default()
+        })
+    }
+
+    /// Manager doing all of its downloads through `fetcher`
+    pub fn with_fetcher(mut self, fetcher: Arc<dyn Fetcher>) -> Self {
+        self.dl = self.dl.with_fetcher(fetcher);
+        self
+    }
+
+    pub fn set_limits(&mut self, domain: &str, limits: Limits) {
+        self.dl.set_limits(domain, limits);
+    }
+
+    pub fn set_parallelism(&mut self, parallelism: Parallelism) {
+        self.dl.set_parallelism(parallelism);
+    }
+
+    pub fn library(&self) -> &Library { &self.lib }
+
+    pub fn config(&self) -> &Config { self.dl.config() }
+
+    /// Progress of everything this manager and its clones do from now on
+    pub fn subscribe(&self) -> Receiver<Event> { self.dl.events().subscribe() }
+
+    fn emit(&self, event: Event) { self.dl.events().emit(event) }
+
+    /// Adds the book found at `source` to the library and, unless only
+    /// tracking it, queues and downloads the chapters `filter` lets through,
+    /// leaving other queued jobs to `drain()`. Nothing is kept if the book's
+    /// index can't be had, a new book that gets none of its chapters is
+    /// rolled back and its files deleted, and a book with only some of them
+    /// is kept and marked incomplete. The report tells which. The filter is
+    /// kept with the book, an empty one doesn't replace the filter of a book
+    /// that's already there.
+    pub async fn add_book(
+        &mut self, bookname: Option<BookName>, source: Source, mode: AddMode,
+        filter: ChapterFilter,
+    ) -> Result<AddBookReport, DownloadError> {
+        let added = self.try_add_book(bookname, source, mode, filter).await;
+        match &added {
+            Ok(report) if report.kept == Kept::RolledBack => {
+                self.emit(Event::Error {
+                    book:  None,
+                    error: report.to_string(),
+                })
+            }
+            Ok(report) => self.emit(Event::Finished {
+                book: Some(report.book.clone()),
+            }),
+            Err(e) => self.emit(Event::Error {
+                book:  None,
+                error: e.to_string(),
+            }),
+        }
+        added
+    }
+
+    async fn try_add_book(
+        &mut self, bookname: Option<BookName>, source: Source, mode: AddMode,
+        filter: ChapterFilter,
+    ) -> Result<AddBookReport, DownloadError> {
+        let src = self.dl.source(source).try_refresh().await?;
+        let bn = bookname.unwrap_or(src.title());
+        let index = src.index().await.try_refresh().await?;
+        let links = index.chapter_links().unwrap_or_default();
+        let mut book = Book::new(bn, index);
+        book.pos = book.index.pos();
         book.set_visual(None);
-        let book = Arc::new(Mutex::new(book));
-        for ch in join_all(
-            src.chapters()
-                .await
-                .unwrap_or_default()
+        book.index.release();
+        let books = self.lib.books.len();
+        let id = self.lib.insert(book).id.clone();
+        let created = self.lib.books.len() > books;
+        let book = self.lib.books.get_mut(&id).expect("The book was inserted");
+        if created || !filter.is_empty() {
+            book.filter = filter;
+            book.meta.remove(Self::WALKED);
+        }
+        let known = book.clone();
+        let urls = known
+            .filter
+            .apply(links.clone())
+            .into_iter()
+            .map(|(url, _)| url)
+            .collect::<Vec<_>>();
+        let mut report = AddBookReport {
+            book: id,
+            name: known.name.clone(),
+            kind: BookKind::from(known.visual()),
+            found: links.len(),
+            skipped: links.len() - urls.len(),
+            ..Default::default()
+        };
+        let id = report.book.clone();
+        self.emit(Event::BookDiscovered {
+            book: id.clone(),
+            name: report.name.clone(),
+        });
+        if mode == AddMode::Track {
+            self.save_book(&id).await;
+            return Ok(report);
+        }
+        let mut jobs = vec![];
+        for url in urls {
+            let num = get_place(&url).map_or(0, |place| place.1);
+            match known.chapters().get(&num).filter(|ch| ch.complete()) {
+                Some(_) => report.downloaded += 1,
+                None => {
+                    let task = Task::Chapter { url: url.clone() };
+                    let job = self.enqueue(&id, task, Priority::Normal).await;
+                    jobs.push((url, num, job));
+                }
+            }
+        }
+        let only = jobs.iter().map(|(_, _, job)| *job).collect();
+        let workers = self.config().workers;
+        let (_, errors) = self.run_jobs(workers, Some(only)).await;
+        let mut errored = false;
+        let book = self.lib.books.get_mut(&id).expect("The book was inserted");
+        for (url, num, job) in jobs {
+            let error = match errors.get(&job) {
+                Some(e) => {
+                    errored = true;
+                    Some(e.to_string())
+                }
+                None => missing(book, num),
+            };
+            match error {
+                Some(e) => {
+                    report.failed.insert(url, e);
+                }
+                None => report.downloaded += 1,
+            }
+        }
+        if errored && created && book.chapters().is_empty() {
+            self.lib.books.remove(&id);
+            let dir = self.config().cache.join(&*id);
+            match std::fs::remove_dir_all(&dir) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                    eprintln!("Couldn't delete {}: {}", dir.display(), e)
+                }
+                _ => {}
+            }
+            report.kept = Kept::RolledBack;
+            return Ok(report);
+        }
+        match report.failed.is_empty() {
+            true => book.meta.remove(Self::INCOMPLETE),
+            false => {
+                report.kept = Kept::Partial;
+                book.meta.insert(Self::INCOMPLETE.into(), "true".into())
+            }
+        };
+        report.kind = BookKind::from(book.visual());
+        self.save_book(&id).await;
+        Ok(report)
+    }
+
+    /// Fetches the page at `url` and its index to show what adding it would
+    /// do, without downloading any chapters
+    pub async fn preview(&self, url: &str) -> Result<Preview, DownloadError> {
+        if let Err(e) = url.parse::<Url>() {
+            return Err(DownloadError::InvalidUrl {
+                url:    url.to_string(),
+                reason: e.to_string(),
+            });
+        }
+        let src = self.dl.source(url.to_string()).try_refresh().await?;
+        let index = src.index().await.try_refresh().await?;
+        let links = index.chapter_links().unwrap_or_default();
+        let nums = links
+            .iter()
+            .filter_map(|(url, _)| get_place(url))
+            .map(|place| place.1)
+            .filter(|&n| n != 0)
+            .collect::<BTreeSet<_>>();
+        let mut meta = src.meta();
+        meta.extend(index.meta());
+        Ok(Preview {
+            url: url.to_string(),
+            index: index.location.clone(),
+            title: src.title(),
+            kind: BookKind::from(index.check_visual()),
+            chapters: links.len(),
+            range: nums
                 .iter()
-                .cloned()
-                .map(|url| async {
-                    let bs = Source::new(url).await;
-                    let domain = bs.domain();
-                    self.sites
-                        .lock()
-                        .await
-                        .entry(domain.clone())
-                        .or_default()
-                        .delay()
-                        .await;
-                    self.dl.chapter(bs, None).await
-                }),
-        )
-        .await
-        .iter()
-        .cloned()
+                .next()
+                .zip(nums.iter().last())
+                .map(|(a, b)| (*a, *b)),
+            first: links.first().map(|(_, title)| title.clone()),
+            last: links.last().map(|(_, title)| title.clone()),
+            meta,
+            confidence: match links.len() {
+                0 => 0.,
+                n => nums.len() as f32 / n as f32,
+            },
+        })
+    }
+
+    /// Replaces the chapters to download for the book from now on, chapters
+    /// already downloaded are kept and the ones it left out are looked at
+    /// again on the next refresh
+    pub async fn set_filter(&mut self, book: &BookId, filter: ChapterFilter) {
+        if let Some(b) = self.lib.books.get_mut(book) {
+            b.filter = filter;
+            b.meta.remove(Self::WALKED);
+            self.save_book(book).await;
+        }
+    }
+
+    /// Looks for chapters past the last known one of every book that isn't
+    /// completed and queues them, writing each book back as soon as its new
+    /// chapters are in
+    pub async fn refresh(&mut self) -> RefreshReport {
+        let (completed, books): (Vec<_>, Vec<_>) = self
+            .lib
+            .books
+            .values()
+            .partition(|b| b.status == BookStatus::Completed);
+        let completed = completed
+            .into_iter()
+            .map(|b| b.id.clone())
+            .collect::<Vec<_>>();
+        let books = books.into_iter().map(|b| b.id.clone()).collect::<Vec<_>>();
+        let mut report = self.refresh_books(&books).await;
+        for id in completed {
+            report.books.insert(id, BookRefresh {
+                skipped: Some("completed".to_string()),
+                ..Default::default()
+            });
+        }
+        report
+    }
+
+    /// Same as refresh() for the given books only, completed ones included
+    pub async fn refresh_books(&mut self, books: &[BookId]) -> RefreshReport {
+        let start = Instant::now();
+        let mut report = RefreshReport::default();
+        let dl = &self.dl;
+        let mut running = FuturesUnordered::new();
+        for book in books.iter().filter_map(|id| self.lib.books.get(id)) {
+            let id = &book.id;
+            let skipped = match book.index.location.is_empty() {
+                true => Some("no source"),
+                false => None,
+            };
+            match skipped {
+                Some(reason) => {
+                    report.books.insert(id.clone(), BookRefresh {
+                        skipped: Some(reason.to_string()),
+                        ..Default::default()
+                    });
+                }
+                None => {
+                    let pred = self.pred(&book.index);
+                    running.push(refresh_book(dl, pred, book.clone()))
+                }
+            }
+        }
+        let mut jobs = vec![];
+        while let Some((id, walk, refresh)) = running.next().await {
+            for (url, allowed) in walk {
+                let job = match allowed {
+                    true => {
+                        let task = Task::Chapter { url: url.clone() };
+                        Some(self.enqueue(&id, task, Priority::Backfill).await)
+                    }
+                    false => None,
+                };
+                jobs.push((id.clone(), url, job));
+            }
+            report.books.insert(id, refresh);
+        }
+        drop(running);
+        let only = jobs.iter().filter_map(|(_, _, job)| *job).collect();
+        let workers = self.config().workers;
+        let (_, errors) = self.run_jobs(workers, Some(only)).await;
+        // The next walk of a book starts past the chapters its filter left
+        // out, unless one it let through before them has to be found again
+        let mut walked = BTreeMap::new();
+        let mut stuck = BTreeSet::new();
+        for (id, url, job) in jobs {
+            let book = match self.lib.books.get(&id) {
+                Some(book) => book,
+                None => continue,
+            };
+            let num = get_place(&url).map_or(0, |place| place.1);
+            let job = match job {
+                Some(job) => job,
+                None if stuck.contains(&id) => continue,
+                None => {
+                    walked.insert(id, url);
+                    continue;
+                }
+            };
+            let refresh = report.books.entry(id.clone()).or_default();
+            let error = match errors.get(&job) {
+                Some(e) => Some(e.to_string()),
+                None => missing(book, num),
+            };
+            match error {
+                Some(e) => {
+                    refresh.failed.insert(num, e);
+                    stuck.insert(id);
+                }
+                None => refresh.new.push(num),
+            }
+        }
+        for (id, url) in walked {
+            if let Some(book) = self.lib.books.get_mut(&id) {
+                book.meta.insert(Self::WALKED.into(), url);
+                self.save_book(&id).await;
+            }
+        }
+        report.elapsed = start.elapsed();
+        self.announce(&report).await;
+        self.emit(Event::Finished { book: None });
+        report
+    }
+
+    /// Settings for, and releases waiting on, notifications
+    pub fn notifier(&self) -> Arc<Mutex<Notifier>> { self.notifier.clone() }
+
+    /// Sends the notifications whose batch is over, or all of them with
+    /// `all`. Returns how many messages went out.
+    pub async fn notify(&self, all: bool) -> usize {
+        let due = self.notifier.lock().await.take(Utc::now(), all);
+        if due.is_empty() {
+            return 0;
+        }
+        let mut sent = 0;
+        let mut failed = vec![];
+        for (notify, digest) in due {
+            match notify.send(&digest).await {
+                Ok(()) => sent += 1,
+                Err(e) => {
+                    self.emit(Event::Error {
+                        book:  None,
+                        error: format!(
+                            "Couldn't send \"{}\": {}",
+                            digest.summary(),
+                            e
+                        ),
+                    });
+                    failed.push(digest);
+                }
+            }
+        }
+        // Saved only now so a crash while sending doesn't lose anything
+        let mut notifier = self.notifier.lock().await;
+        failed
+            .into_iter()
+            .for_each(|digest| notifier.restore(digest));
+        save_notifier(&notifier);
+        sent
+    }
+
+    /// Queues notifications of the chapters the refresh found
+    async fn announce(&self, report: &RefreshReport) {
+        let mut notifier = self.notifier.lock().await;
+        let pending = notifier.pending().len();
+        for (id, refresh) in
+            report.books.iter().filter(|(_, r)| !r.new.is_empty())
         {
-            let mut book = book.lock().await;
-            book.add_chapter(ch).await;
+            notifier.record(Release {
+                book:     id.clone(),
+                name:     self.lib.books[id].name.clone(),
+                chapters: refresh.new.clone(),
+                found:    Utc::now(),
+            });
+        }
+        if notifier.pending().len() > pending {
+            save_notifier(&notifier);
+        }
+        drop(notifier);
+        self.notify(false).await;
+    }
+
+    /// When books were and will be checked by `daemon()`
+    pub fn watch(&self) -> Arc<Mutex<Watch>> { self.watch.clone() }
+
+    /// Checks the book every `interval` instead of going by its status
+    pub async fn set_interval(&self, book: &BookId, interval: Option<Duration>) {
+        let mut watch = self.watch.lock().await;
+        watch.set_interval(book, interval);
+        save_watch(&watch);
+    }
+
+    /// Keeps checking books for new chapters, each on its own schedule,
+    /// until `stop` completes. A check that has started is finished first.
+    pub async fn daemon(&mut self, stop: impl Future<Output = ()>) {
+        tokio::pin!(stop);
+        loop {
+            self.notify(false).await;
+            let (due, next) = {
+                let watch = self.watch.lock().await;
+                (watch.due(&self.lib, Utc::now()), watch.next_due(&self.lib))
+            };
+            if due.is_empty() {
+                let wait = next
+                    .and_then(|next| (next - Utc::now()).to_std().ok())
+                    .unwrap_or(Self::IDLE)
+                    .min(Self::IDLE);
+                tokio::select! {
+                    _ = &mut stop => return,
+                    _ = tokio::time::sleep(wait) => continue,
+                }
+            }
+            self.refresh_books(&due).await;
+            let mut watch = self.watch.lock().await;
+            for book in due.iter().filter_map(|id| self.lib.books.get(id)) {
+                watch.checked(book, Utc::now());
+            }
+            watch.prune(&self.lib);
+            save_watch(&watch);
+        }
+    }
+
+    /// The download queue, shared with the workers of `drain()`
+    pub fn queue(&self) -> Arc<Mutex<Queue>> { self.queue.clone() }
+
+    pub async fn enqueue(
+        &self, book: &BookId, task: Task, priority: Priority,
+    ) -> u64 {
+        let mut queue = self.queue.lock().await;
+        let url = match &task {
+            Task::Chapter { url } => Some(url.clone()),
+            _ => None,
+        };
+        let id = queue.push(book.clone(), task, priority);
+        save_queue(&queue);
+        if let Some(url) = url {
+            self.emit(Event::ChapterQueued {
+                book: book.clone(),
+                url,
+                job: id,
+            });
         }
+        id
     }
 
-    pub async fn refresh(&mut self) -> u32 {
-        let tmp = self.lib.books.clone();
-        let filter = true; // self.sites.lock().await.contains_key(&*name); //TODO: filter for some books
-        let iter = tmp
+    /// Moves the chapter ahead of everything else in the queue
+    pub async fn reading(&self, book: &BookId, chapter: u16) -> Option<u64> {
+        let ch = self.lib.books.get(book)?.chapters().get(&chapter)?;
+        let task = Task::Chapter {
+            url: ch.page.location.clone(),
+        };
+        Some(self.enqueue(book, task, Priority::Reading).await)
+    }
+
+    /// Queues the chapters of the book that aren't complete yet, those in
+    /// `range` or else the ones its filter lets through, and downloads them
+    /// with up to `workers` at a time. Returns how many jobs were done.
+    pub async fn download(
+        &mut self, book: &BookId, range: Option<Range>, workers: usize,
+    ) -> Result<usize, DownloadError> {
+        let book = match self.lib.books.get(book) {
+            Some(book) => book,
+            None => return Ok(0),
+        };
+        let index = self.dl.source(book.index.clone()).try_refresh().await?;
+        let filter = ChapterFilter {
+            range: range.or(book.filter.range),
+            ..book.filter.clone()
+        };
+        let missing = filter
+            .apply(index.chapter_links().unwrap_or_default())
+            .into_iter()
+            .map(|(url, _)| url)
+            .filter(|url| {
+                book.chapters()
+                    .get(&get_place(url).map_or(0, |place| place.1))
+                    .map_or(true, |ch| !ch.complete())
+            })
+            .collect::<Vec<_>>();
+        let id = book.id.clone();
+        for url in missing {
+            self.enqueue(&id, Task::Chapter { url }, Priority::Normal)
+                .await;
+        }
+        Ok(self.drain(workers).await)
+    }
+
+    /// Takes the book out of the library along with its queued jobs, its
+    /// files are left on disk
+    pub async fn remove_book(&mut self, book: &BookId) -> Option<Book> {
+        let removed = self.lib.books.remove(book)?;
+        self.save_book(book).await;
+        let mut queue = self.queue.lock().await;
+        queue.cancel_book(book);
+        queue.prune();
+        save_queue(&queue);
+        drop(queue);
+        let mut watch = self.watch.lock().await;
+        watch.prune(&self.lib);
+        save_watch(&watch);
+        Some(removed)
+    }
+
+    pub async fn rename_book(&mut self, book: &BookId, name: BookName) {
+        self.lib.rename_book(book, name);
+        self.save_book(book).await;
+    }
+
+    /// Checks every downloaded file, see `Library::verify()`
+    pub fn verify(&self) -> Report { self.lib.verify() }
+
+    /// Downloads the broken pages of the report again, saving the books
+    /// they belong to. Returns how many pages were fixed.
+    pub async fn repair(&mut self, report: &Report) -> usize {
+        let fixed = self.dl.repair(&mut self.lib, report).await;
+        let books = report
+            .problems
             .iter()
-            .filter(|(_name, _)| filter)
-            .map(|(a, b)| (a.clone(), b.clone()));
-        let newchps = join_all(iter.map(|(a, b)| async {
-            let (name, book) = (a, b);
-            let pred = self.pred(&book.index);
-            let mut sources = vec![];
-            let mut source = book.index.next(&pred).await;
-            while let Some(src) = source {
-                if sources.contains(&src) {
-                    break;
+            .map(|p| p.book.clone())
+            .collect::<BTreeSet<_>>();
+        for book in &books {
+            self.save_book(book).await;
+        }
+        fixed
+    }
+
+    /// Runs queued jobs with up to `workers` at a time until none are left,
+    /// saving every book as soon as one of its jobs is done, and forgets the
+    /// finished ones at the end. Returns how many jobs were done.
+    pub async fn drain(&mut self, workers: usize) -> usize {
+        self.run_jobs(workers, None).await.0
+    }
+
+    /// Same as drain(), along with why each job that failed did. With `only`
+    /// just those jobs and the ones they queue are run, the rest are left to
+    /// whoever drains the queue.
+    async fn run_jobs(
+        &mut self, workers: usize, mut only: Option<BTreeSet<u64>>,
+    ) -> (usize, BTreeMap<u64, DownloadError>) {
+        let dl = &self.dl;
+        let mut running = FuturesUnordered::new();
+        let mut done = 0;
+        let mut errors = BTreeMap::new();
+        loop {
+            let mut queue = self.queue.lock().await;
+            let mut handed = false;
+            while running.len() < workers.max(1) {
+                let next = match &only {
+                    Some(ids) => queue.next_of(ids),
+                    None => queue.next(),
                 };
-                sources.push(src.clone());
-                self.clone().delay(&name).await;
-                source = src.next(&pred).await;
-            }
-            let iter2 = sources.iter().cloned().map(|a| a.clone());
-            join_all(iter2.map(|next| async {
-                let mut book = book.clone();
-                self.clone().delay(&name).await;
-                let ch = self.dl.clone().chapter(next, book.visual()).await;
-                book.add_chapter(ch).await
-            }))
-            .await
-        }))
-        .await;
-        newchps.iter().map(|a| a).fold(0u32, |mut a, _b| {
-            a += 1;
-            a
-        })
+                let job = match next {
+                    Some(job) => job,
+                    None => break,
+                };
+                handed = true;
+                match self.lib.books.get(&job.book) {
+                    Some(book) => running.push(run(dl, job, book.clone())),
+                    None => queue.finish(job.id, Err("no such book".to_string())),
+                }
+            }
+            if handed {
+                save_queue(&queue);
+            }
+            drop(queue);
+            let (job, outcome) = match running.next().await {
+                Some(finished) => finished,
+                None => break,
+            };
+            let mut queue = self.queue.lock().await;
+            if queue.get(job.id).map(|j| &j.state) != Some(&JobState::Running) {
+                continue;
+            }
+            let book = match self.lib.books.get_mut(&job.book) {
+                Some(book) => book,
+                None => continue,
+            };
+            let result = match outcome {
+                Ok(Outcome::Chapters(urls)) => {
+                    urls.into_iter()
+                        .filter(|url| {
+                            book.chapters()
+                                .get(&get_place(url).map_or(0, |place| place.1))
+                                .map_or(true, |ch| !ch.complete())
+                        })
+                        .for_each(|url| {
+                            let task = Task::Chapter { url: url.clone() };
+                            let id = queue.push(
+                                job.book.clone(),
+                                task,
+                                Priority::Backfill,
+                            );
+                            only.as_mut().map(|ids| ids.insert(id));
+                            dl.events().emit(Event::ChapterQueued {
+                                book: job.book.clone(),
+                                url,
+                                job: id,
+                            });
+                        });
+                    Ok(())
+                }
+                Ok(Outcome::Chapter(dl)) => {
+                    let chapter = dl.chapter.num();
+                    for (num, _) in dl.errors {
+                        let url = dl.chapter.pages()[&num].url.clone();
+                        let task = Task::Page { chapter, num, url };
+                        let id = queue.push(
+                            job.book.clone(),
+                            task,
+                            Priority::Backfill,
+                        );
+                        only.as_mut().map(|ids| ids.insert(id));
+                    }
+                    book.add_chapter(dl.chapter).await;
+                    Ok(())
+                }
+                Ok(Outcome::Page(chapter, cnt)) => {
+                    book.chapter_mut(chapter).map(|ch| ch.add_content(cnt));
+                    Ok(())
+                }
+                Err(e) => {
+                    dl.events().emit(Event::Error {
+                        book:  Some(job.book.clone()),
+                        error: e.to_string(),
+                    });
+                    let error = e.to_string();
+                    errors.insert(job.id, e);
+                    Err(error)
+                }
+            };
+            queue.finish(job.id, result);
+            save_queue(&queue);
+            drop(queue);
+            done += 1;
+            self.save_book(&job.book).await;
+        }
+        let mut queue = self.queue.lock().await;
+        queue.prune();
+        save_queue(&queue);
+        drop(queue);
+        dl.events().emit(Event::Finished { book: None });
+        (done, errors)
     }
 
-    pub async fn delay(&mut self, name: &String) -> Option<String> {
-        let mut site = self.sites.lock().await;
-        let s = site.get_mut(name).unwrap();
-        s.delay().await;
-        s.next.clone()
+    async fn save_book(&self, id: &BookId) {
+        if let Err(e) = self.lib.save_book(id).await {
+            eprintln!("Couldn't save {}: {}", id, e);
+        }
     }
 
+    /// Text of the link to the source's next chapter
     pub fn pred(&self, source: &Source) -> String {
-        let default = "Next";
-        self.preds
-            .get(&source.domain())
-            .cloned()
-            .unwrap_or(default.to_string())
+        self.dl
+            .config()
+            .site(&source.domain().unwrap_or_default())
+            .next
+            .unwrap_or_default()
     }
 }
+
+impl RefreshReport {
+    pub fn new_chapters(&self) -> usize {
+        self.books.values().map(|b| b.new.len()).sum()
+    }
+
+    pub fn failed_chapters(&self) -> usize {
+        self.books.values().map(|b| b.failed.len()).sum()
+    }
+}
+impl fmt::Display for AddBookReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} of {} chapters downloaded, {} failed",
+            self.name.as_str(),
+            self.downloaded,
+            self.found,
+            self.failed.len()
+        )?;
+        match self.kept {
+            Kept::Complete => Ok(()),
+            Kept::Partial => write!(f, ", kept as incomplete"),
+            Kept::RolledBack => write!(f, ", not added"),
+        }
+    }
+}
+impl fmt::Display for Preview {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} ({:?}) at {}",
+            self.title.as_str(),
+            self.kind,
+            self.index
+        )?;
+        write!(f, "{} chapters", self.chapters)?;
+        if let Some((first, last)) = self.range {
+            write!(f, ", {} to {}", first, last)?;
+        }
+        if let (Some(first), Some(last)) = (&self.first, &self.last) {
+            write!(f, ": \"{}\" .. \"{}\"", first, last)?;
+        }
+        write!(f, " ({:.0}% sure)", self.confidence * 100.)
+    }
+}
+impl fmt::Display for RefreshReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let skipped = self.books.values().filter(|b| b.skipped.is_some());
+        write!(
+            f,
+            "{} new chapters, {} failed, {} of {} books skipped in {:.1?}",
+            self.new_chapters(),
+            self.failed_chapters(),
+            skipped.count(),
+            self.books.len(),
+            self.elapsed
+        )
+    }
+}
+
+/// Does the job's downloading, leaving the library alone
+async fn run(
+    dl: &Retriever, job: Job, book: Book,
+) -> (Job, Result<Outcome, DownloadError>) {
+    let outcome = match &job.task {
+        Task::Book => match dl.source(book.index.clone()).try_refresh().await {
+            Ok(index) => Ok(Outcome::Chapters(
+                book.filter
+                    .apply(index.chapter_links().unwrap_or_default())
+                    .into_iter()
+                    .map(|(url, _)| url)
+                    .collect(),
+            )),
+            Err(e) => Err(e),
+        },
+        Task::Chapter { url } => {
+            let slot = dl.slot(&job.book, url).await;
+            match dl.try_fetch(url.clone()).await {
+                Ok(src) => {
+                    drop(slot);
+                    let known = book.chapters().get(&src.place.1);
+                    let ch =
+                        dl.chapter(&job.book, src, book.visual(), known).await;
+                    Ok(Outcome::Chapter(ch))
+                }
+                Err(e) => Err(e),
+            }
+        }
+        Task::Page { chapter, num, url } => {
+            let pages = book.chapters().get(chapter).map(|ch| ch.pages());
+            let visual = book.visual().unwrap_or_else(|| {
+                pages
+                    .into_iter()
+                    .flat_map(|p| p.values())
+                    .filter_map(|c| c.mime.as_ref())
+                    .any(|m| m.starts_with("image/"))
+            });
+            let dir =
+                dl.config().cache.join(&*job.book).join(chapter.to_string());
+            let _slot = dl.slot(&job.book, url).await;
+            let known = pages.and_then(|p| p.get(num));
+            dl.content(url, *num, visual, &dir, known)
+                .await
+                .map(|cnt| Outcome::Page(*chapter, cnt))
+        }
+    };
+    (job, outcome)
+}
+
+fn save_queue(queue: &Queue) {
+    if let Err(e) = queue.save() {
+        eprintln!("Couldn't save the download queue: {}", e);
+    }
+}
+
+fn save_notifier(notifier: &Notifier) {
+    if let Err(e) = notifier.save() {
+        eprintln!("Couldn't save the notifications: {}", e);
+    }
+}
+
+fn save_watch(watch: &Watch) {
+    if let Err(e) = watch.save() {
+        eprintln!("Couldn't save the update schedule: {}", e);
+    }
+}
+
+/// Follows the "next" links from the last known chapter of the book, or the
+/// last one walked past if that's further, until they run out or lead
+/// somewhere already known, taking a download slot for every page like the
+/// downloads do. Returns the chapters it finds in order, along with whether
+/// the book's filter lets them through.
+async fn refresh_book(
+    dl: &Retriever, pred: String, book: Book,
+) -> (BookId, Vec<(String, bool)>, BookRefresh) {
+    let mut refresh = BookRefresh::default();
+    let known = |num| book.chapters().get(&num).filter(|ch| ch.complete());
+    let last = book.chapters().iter().last();
+    let walked = book.meta.get(Manager::WALKED).filter(|url| {
+        last.map_or(true, |(num, _)| {
+            get_place(url).map_or(0, |place| place.1) > *num
+        })
+    });
+    let start = match (walked, last) {
+        (Some(url), _) => Source::from(url.clone()),
+        (None, Some((_, ch))) => ch.page.clone(),
+        (None, None) => book.index.clone(),
+    };
+    let slot = dl.slot(&book.id, &start.location).await;
+    let first = match dl.source(start).try_refresh().await {
+        Ok(src) => src.try_next(&pred).await,
+        Err(e) => Err(e),
+    };
+    drop(slot);
+    let mut next = match first {
+        Ok(next) => next,
+        Err(e) => {
+            refresh.skipped = Some(e.to_string());
+            return (book.id.clone(), vec![], refresh);
+        }
+    };
+    let mut sources: Vec<Source> = vec![];
+    while let Some(src) = next {
+        if sources.contains(&src) || known(src.place.1).is_some() {
+            break;
+        }
+        // What was found so far is still downloaded
+        let _slot = dl.slot(&book.id, &src.location).await;
+        next = src.try_next(&pred).await.unwrap_or_else(|e| {
+            refresh.stopped = Some(e.to_string());
+            None
+        });
+        sources.push(src);
+    }
+    let walk = sources
+        .into_iter()
+        .map(|src| {
+            let allowed = book.filter.allows(&src.location, &src.page_title());
+            if !allowed {
+                refresh.filtered.push(src.place.1);
+            }
+            (src.location, allowed)
+        })
+        .collect();
+    (book.id.clone(), walk, refresh)
+}
+
+/// Why the chapter can't be read in full, `None` once it can
+fn missing(book: &Book, num: u16) -> Option<String> {
+    let ch = match book.chapters().get(&num) {
+        Some(ch) if ch.complete() => return None,
+        Some(ch) => ch,
+        None => return Some("not downloaded".to_string()),
+    };
+    let fault = ch.pages().values().find_map(|c| c.verify().err());
+    Some(fault.map_or("pages are missing".to_string(), |f| f.to_string()))
+}
+
+#[tokio::test]
+async fn refresh_report() {
+    use crate::{fetch::Mock, library::Chapter};
+
+    let mock = Mock::default();
+    let page = |n: u16, next: bool| {
+        let link = match next {
+            true => format!(
+                "<a href=\"https://mock.novel.test/chapter-{}\">Next</a>",
+                n + 1
+            ),
+            false => String::new(),
+        };
+        format!(
+            "<html><title>Book Chapter {}</title><div>{}</div></html>",
+            n, link
+        )
+    };
+    let pages = [(1, true), (2, true), (3, true), (4, false)];
+    for (n, next) in pages.iter().cloned() {
+        let url = format!("https://mock.novel.test/chapter-{}", n);
+        mock.page(&url, &page(n, next));
+    }
+    let cache = std::env::temp_dir()
+        .join(format!("ehound-refresh-{}", std::process::id()));
+    let config = Config {
+        cache: cache.clone(),
+        ..Default::default()
+    };
+    let mut manager = Manager::open(config)
+        .await
+        .unwrap()
+        .with_fetcher(Arc::new(mock));
+    let index = Source::from("https://mock.novel.test/book".to_string());
+    let mut book = Book::new("Book".to_string().into(), index);
+    book.set_visual(Some(false));
+    book.filter = ChapterFilter::default().exclude("chapter 4").unwrap();
+    let mut ch = Chapter::default();
+    ch.page = "https://mock.novel.test/chapter-1".to_string().into();
+    book.add_chapter(ch).await;
+    let id = manager.lib.insert(book).id.clone();
+    manager.lib.insert(Book::from("Untracked".to_string()));
+
+    let report = manager.refresh().await;
+    assert_eq!(report.books[&id].new, vec![2, 3]);
+    assert_eq!(report.books[&id].filtered, vec![4]);
+    assert_eq!(report.new_chapters(), 2);
+    assert_eq!(
+        report
+            .books
+            .values()
+            .filter(|b| b.skipped.is_some())
+            .count(),
+        1
+    );
+    assert_eq!(manager.library().books[&id].chapters().len(), 3);
+    let ch = &manager.library().books[&id].chapters()[&3];
+    assert!(ch.pages().values().all(|c| c.path.starts_with(&cache)));
+
+    // The next refresh starts past the chapter the filter left out
+    let report = manager.refresh_books(&[id.clone()]).await;
+    assert!(report.books[&id].filtered.is_empty());
+    std::fs::remove_dir_all(cache).ok();
+}
+
+#[tokio::test]
+async fn add_book_report() {
+    use crate::fetch::Mock;
+
+    let mock = Mock::default();
+    let index = |host: &str, chapters: &[u16]| {
+        let links = chapters
+            .iter()
+            .map(|n| {
+                format!("<a href=\"https://{}/chapter-{}\">{}</a>", host, n, n)
+            })
+            .collect::<String>();
+        // Links that aren't chapters, or even pages, are left out
+        let other = "<a href=\"mailto:book@novel.test\">Mail</a>\
+                     <a href=\"javascript:void(0)\">Top</a>";
+        format!(
+            "<html><title>Book</title><div><p>{}{}</p></div></html>",
+            links, other
+        )
+    };
+    for host in &["a.novel.test", "b.novel.test"] {
+        let chapters = match *host {
+            "a.novel.test" => vec![1, 2, 3],
+            _ => vec![4],
+        };
+        for url in &[format!("https://{}", host), format!("https://{}/", host)] {
+            mock.page(url, &index(host, &chapters));
+        }
+    }
+    for n in 1..=2 {
+        mock.page(
+            &format!("https://a.novel.test/chapter-{}", n),
+            &format!("<html><title>Book Chapter {}</title><p>Text</p></html>", n),
+        );
+    }
+    let cache =
+        std::env::temp_dir().join(format!("ehound-add-{}", std::process::id()));
+    let config = Config {
+        cache: cache.clone(),
+        ..Default::default()
+    };
+    let mut manager = Manager::open(config)
+        .await
+        .unwrap()
+        .with_fetcher(Arc::new(mock.clone()));
+
+    // Only the page and its index are fetched for a preview
+    let preview = manager.preview("https://a.novel.test").await.unwrap();
+    assert_eq!((preview.chapters, preview.range), (3, Some((1, 3))));
+    assert_eq!(preview.last.as_deref(), Some("3"));
+    assert!(mock.requests().iter().all(|r| !r.contains("chapter")));
+    assert!(matches!(
+        manager.preview("a.novel.test").await,
+        Err(DownloadError::InvalidUrl { .. })
+    ));
+
+    let source = |url: &str| Source::from(url.to_string());
+    let skip = ChapterFilter::default().exclude("^1$").unwrap();
+    let report = manager
+        .add_book(None, source("https://a.novel.test"), AddMode::Track, skip)
+        .await
+        .unwrap();
+    assert_eq!((report.found, report.skipped, report.downloaded), (3, 1, 0));
+    assert!(manager.lib.books[&report.book].chapters().is_empty());
+
+    // Chapter 1 is still filtered out and 3 is missing, chapter 2 is kept and
+    // the book marked incomplete
+    let report = manager
+        .add_book(
+            None,
+            source("https://a.novel.test"),
+            AddMode::Download,
+            ChapterFilter::default(),
+        )
+        .await
+        .unwrap();
+    assert_eq!((report.downloaded, report.failed.len()), (1, 1));
+    assert_eq!((report.kind, report.kept), (BookKind::Text, Kept::Partial));
+    let book = &manager.lib.books[&report.book];
+    assert!(book.meta.contains_key(Manager::INCOMPLETE));
+    let queue = manager.queue.lock().await;
+    let failed = queue
+        .jobs()
+        .filter(|j| matches!(j.state, JobState::Failed(_)));
+    assert_eq!(failed.map(|j| j.priority).collect::<Vec<_>>(), vec![
+        Priority::Normal
+    ]);
+    drop(queue);
+
+    // None of its chapters could be had, so the book is rolled back
+    let books = manager.lib.books.len();
+    let report = manager
+        .add_book(
+            None,
+            source("https://b.novel.test"),
+            AddMode::Download,
+            ChapterFilter::default(),
+        )
+        .await
+        .unwrap();
+    assert_eq!((report.kept, report.failed.len()), (Kept::RolledBack, 1));
+    assert_eq!(manager.lib.books.len(), books);
+    assert!(!cache.join(&*report.book).exists());
+
+    // Offline, only what the page cache has can be had
+    let offline = Retriever::new(Arc::new(Config {
+        cache: cache.clone(),
+        mode: crate::cache::Mode::Offline,
+        ..Default::default()
+    }));
+    let page = |url: &str| {
+        let src = offline.source(source(url));
+        async move { src.try_refresh().await }
+    };
+    assert!(page("https://a.novel.test").await.is_ok());
+    assert!(matches!(
+        page("https://c.novel.test").await,
+        Err(DownloadError::Offline { .. })
+    ));
+    std::fs::remove_dir_all(cache).ok();
+}
@@ -0,0 +1,256 @@
+use crate::{
+    library::{digest, BookId, Chapter, Content, ContentStatus, Library},
+    media::{self, Format},
+    retriever::Retriever,
+    text::Text,
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fmt};
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct Report {
+    pub checked:  usize,
+    pub problems: Vec<Problem>,
+}
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Problem {
+    pub book:    BookId,
+    pub chapter: u16,
+    pub page:    u16,
+    pub fault:   Fault,
+}
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Fault {
+    /// The file isn't on disk
+    Missing,
+    Empty,
+    HashMismatch,
+    /// Doesn't decode as an image or parse as text
+    Undecodable,
+    /// The last download attempt failed
+    Failed(String),
+    /// The page is absent from the chapter's sequence
+    Gap,
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool { self.problems.is_empty() }
+
+    /// Broken pages grouped by book and chapter
+    pub fn by_chapter(&self) -> BTreeMap<(BookId, u16), Vec<u16>> {
+        let mut map = BTreeMap::<_, Vec<_>>::new();
+        self.problems.iter().for_each(|p| {
+            map.entry((p.book.clone(), p.chapter))
+                .or_default()
+                .push(p.page)
+        });
+        map
+    }
+}
+
+impl Library {
+    /// Checks every file of every chapter against what was recorded for it
+    pub fn verify(&self) -> Report {
+        let mut report = Report::default();
+        for (id, book) in &self.books {
+            for (&chapter, ch) in book.chapters() {
+                let mut problem = |page, fault| {
+                    report.problems.push(Problem {
+                        book: id.clone(),
+                        chapter,
+                        page,
+                        fault,
+                    })
+                };
+                ch.pages()
+                    .iter()
+                    .filter_map(|(&page, cnt)| {
+                        cnt.verify().err().map(|f| (page, f))
+                    })
+                    .for_each(|(page, fault)| problem(page, fault));
+                match ch.pages().is_empty() {
+                    true => problem(0, Fault::Gap),
+                    false => {
+                        ch.gaps().into_iter().for_each(|p| problem(p, Fault::Gap))
+                    }
+                }
+                report.checked += ch.pages().len();
+            }
+        }
+        report
+    }
+}
+
+impl Chapter {
+    /// Every page is downloaded and intact, so there's nothing to download
+    pub fn complete(&self) -> bool {
+        !self.pages().is_empty() &&
+            self.gaps().is_empty() &&
+            self.pages().values().all(Content::downloaded)
+    }
+}
+
+impl Content {
+    /// Downloaded and intact, placeholders pass `verify` but aren't
+    pub fn downloaded(&self) -> bool { self.is_ok() && self.verify().is_ok() }
+
+    pub fn verify(&self) -> Result<(), Fault> {
+        match &self.status {
+            ContentStatus::Failed(reason) => {
+                return Err(Fault::Failed(reason.clone()))
+            }
+            ContentStatus::Missing => return Err(Fault::Missing),
+            ContentStatus::Placeholder => return Ok(()),
+            ContentStatus::Ok => {}
+        }
+        let data = std::fs::read(&self.path).map_err(|_| Fault::Missing)?;
+        if data.is_empty() {
+            return Err(Fault::Empty);
+        }
+        if self.sha256.as_ref().map_or(false, |h| *h != digest(&data)) {
+            return Err(Fault::HashMismatch);
+        }
+        match self.mime.as_deref() {
+            Some(m) if m.starts_with("image/") => {
+                Format::sniff(&data).and(media::dimensions(&data)).is_some()
+            }
+            Some(_) => serde_json::from_slice::<Text>(&data).is_ok(),
+            // Migrated from before formats were recorded
+            None => {
+                Format::sniff(&data).is_some() ||
+                    serde_json::from_slice::<Text>(&data).is_ok()
+            }
+        }
+        .then(|| ())
+        .ok_or(Fault::Undecodable)
+    }
+}
+
+impl Retriever {
+    /// Downloads again only the pages listed in the report, returning how
+    /// many of them were fixed
+    pub async fn repair(&self, lib: &mut Library, report: &Report) -> usize {
+        let mut fixed = 0;
+        for ((id, chapter), pages) in report.by_chapter() {
+            let book = match lib.books.get_mut(&id) {
+                Some(book) => book,
+                None => continue,
+            };
+            let visual = book.visual();
+            let ch = match book.chapter_mut(chapter) {
+                Some(ch) => ch,
+                None => continue,
+            };
+            let vis = visual.unwrap_or_else(|| {
+                ch.pages()
+                    .values()
+                    .filter_map(|c| c.mime.as_ref())
+                    .any(|m| m.starts_with("image/"))
+            });
+            let dir = ch
+                .pages()
+                .values()
+                .find_map(|c| c.path.parent().map(|p| p.to_path_buf()))
+                .filter(|p| !p.as_os_str().is_empty())
+                .unwrap_or_else(|| {
+                    self.config().cache.join(&*id).join(chapter.to_string())
+                });
+            // Pages that were never recorded need the chapter's page list again
+            let urls = match pages.iter().any(|p| !ch.pages().contains_key(p)) {
+                true => match self.source(ch.page.clone()).try_refresh().await {
+                    Ok(src) if vis => src.images_batch().unwrap_or_default(),
+                    Ok(src) => vec![src.location.clone()],
+                    Err(e) => {
+                        eprintln!("Couldn't list the pages of {}: {}", id, e);
+                        vec![]
+                    }
+                },
+                false => vec![],
+            };
+            for page in pages {
+                let url = match ch.pages().get(&page) {
+                    Some(c) if !c.url.is_empty() => c.url.clone(),
+                    _ => match urls.get(page as usize) {
+                        Some(url) => url.clone(),
+                        None => continue,
+                    },
+                };
+                let slot = self.slot(&id, &url).await;
+                let known = ch.pages().get(&page);
+                let cnt = self.content(&url, page, vis, &dir, known).await;
+                drop(slot);
+                let cnt = cnt.unwrap_or_else(|e| {
+                    let mut cnt = Content::new(page, url);
+                    cnt.fail(e.to_string());
+                    cnt
+                });
+                cnt.is_ok().then(|| fixed += 1);
+                ch.add_content(cnt);
+            }
+        }
+        fixed
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fault::Missing => write!(f, "file is missing"),
+            Fault::Empty => write!(f, "file is empty"),
+            Fault::HashMismatch => write!(f, "hash doesn't match"),
+            Fault::Undecodable => write!(f, "file can't be decoded"),
+            Fault::Failed(reason) => write!(f, "download failed: {}", reason),
+            Fault::Gap => write!(f, "page is missing from the sequence"),
+        }
+    }
+}
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} chapter {} page {}: {}",
+            self.book, self.chapter, self.page, self.fault
+        )
+    }
+}
+
+#[tokio::test]
+async fn library_verify() {
+    use crate::library::Book;
+
+    let dir = std::env::temp_dir()
+        .join(format!("ehound-verify-{}", std::process::id()));
+    let mut ch = Chapter::default();
+    ch.page = "https://example.com/book/chapter-3".to_string().into();
+    let mut good = Content::new(0, "https://example.com/0.png".to_string());
+    good.path = dir.join("0000.png");
+    good.mime = Some("image/png".to_string());
+    good.save(
+        b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR\0\0\0\x10\0\0\0\x20\x08\x06\0\0\0",
+    )
+    .unwrap();
+    let mut bad = good.clone();
+    (bad.num, bad.path) = (2, dir.join("0002.png"));
+    bad.save(b"<html>403 Forbidden</html>").unwrap();
+    ch.add_content(good);
+    ch.add_content(bad);
+    let mut book = Book::from("Book".to_string());
+    book.add_chapter(ch).await;
+    let mut lib = Library::default();
+    lib.insert(book);
+
+    let report = lib.verify();
+    assert_eq!(report.checked, 2);
+    let faults = report.problems.iter().map(|p| (p.page, p.fault.clone()));
+    assert_eq!(faults.collect::<Vec<_>>(), vec![
+        (2, Fault::Undecodable),
+        (1, Fault::Gap)
+    ]);
+
+    // A placeholder passes the check but is still to be downloaded
+    let mut ch = Chapter::default();
+    ch.add_content(Content::new(0, "https://example.com/0.png".to_string()));
+    assert!(ch.pages()[&0].verify().is_ok());
+    assert!(!ch.complete());
+    std::fs::remove_dir_all(dir).ok();
+}
@@ -0,0 +1,342 @@
+use crate::{
+    config::Config,
+    library::{Book, BookId, BookStatus, Chapter, Library},
+};
+use chrono::{DateTime, Duration as Span, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap},
+    fs::File,
+    hash::{Hash, Hasher},
+    io::{self, BufReader},
+    path::PathBuf,
+    time::Duration,
+};
+
+impl Default for Intervals {
+    fn default() -> Self {
+        Self {
+            ongoing:   Duration::from_secs(6 * 3600),
+            unknown:   Duration::from_secs(24 * 3600),
+            hiatus:    Duration::from_secs(7 * 24 * 3600),
+            completed: Duration::from_secs(30 * 24 * 3600),
+            spacing:   Duration::from_secs(60),
+        }
+    }
+}
+
+impl Default for Watch {
+    fn default() -> Self {
+        Self {
+            intervals: Intervals::default(),
+            books:     BTreeMap::new(),
+            location:  Config::default().cache.join("watch.json"),
+        }
+    }
+}
+
+/// How long to wait between two checks of a book, by its status
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Intervals {
+    pub ongoing:   Duration,
+    pub unknown:   Duration,
+    pub hiatus:    Duration,
+    pub completed: Duration,
+    /// Least time between two checks of books on the same domain
+    pub spacing:   Duration,
+}
+
+/// When books were checked for new chapters and when they're due again,
+/// kept on disk so the schedule survives restarts
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Watch {
+    /// Set from the config, only the checks are kept
+    #[serde(skip)]
+    pub intervals: Intervals,
+    books:         BTreeMap<BookId, Checks>,
+    #[serde(skip)]
+    location:      PathBuf,
+}
+#[derive(Default, Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Checks {
+    /// Replaces the interval that goes with the book's status
+    pub interval: Option<Duration>,
+    pub last:     Option<DateTime<Utc>>,
+    pub next:     Option<DateTime<Utc>>,
+}
+
+/// How often a book gets new chapters, learned from when its chapters were
+/// first downloaded
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Cadence {
+    pub period:   Span,
+    pub expected: DateTime<Utc>,
+}
+
+impl Cadence {
+    /// Chapters downloaded within this long of each other came out together
+    const BATCH: i64 = 3600;
+    /// Checks are made this long after a release is expected
+    const MARGIN: i64 = 15 * 60;
+
+    /// Needs at least three releases to go by
+    pub fn learn(book: &Book) -> Option<Self> {
+        let mut dates = book
+            .chapters()
+            .values()
+            .filter_map(released)
+            .collect::<Vec<_>>();
+        dates.sort();
+        let mut releases: Vec<DateTime<Utc>> = vec![];
+        for date in dates {
+            match releases.last() {
+                Some(&last) if (date - last).num_seconds() < Self::BATCH => {}
+                _ => releases.push(date),
+            }
+        }
+        let mut gaps =
+            releases.windows(2).map(|w| w[1] - w[0]).collect::<Vec<_>>();
+        if gaps.len() < 2 {
+            return None;
+        }
+        gaps.sort();
+        let period = gaps[gaps.len() / 2];
+        Some(Self {
+            period,
+            expected: *releases.last()? + period,
+        })
+    }
+
+    /// The first expected release after `time`
+    pub fn after(&self, time: DateTime<Utc>) -> DateTime<Utc> {
+        match time < self.expected {
+            true => self.expected,
+            false => {
+                let periods = (time - self.expected).num_seconds() /
+                    self.period.num_seconds().max(1) +
+                    1;
+                self.expected + self.period * periods as i32
+            }
+        }
+    }
+}
+
+impl Watch {
+    /// The schedule saved at `location`, or an empty one
+    pub fn open(location: PathBuf) -> io::Result<Self> {
+        let mut watch: Self = match File::open(&location) {
+            Ok(file) => serde_json::from_reader(BufReader::new(file))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Self::default(),
+            Err(e) => return Err(e),
+        };
+        watch.location = location;
+        Ok(watch)
+    }
+
+    /// Written next to the location and renamed into place
+    pub fn save(&self) -> io::Result<()> {
+        self.location
+            .parent()
+            .map(std::fs::create_dir_all)
+            .transpose()?;
+        let tmp = self.location.with_extension("json.tmp");
+        serde_json::to_writer(File::create(&tmp)?, self)?;
+        std::fs::rename(tmp, &self.location)
+    }
+
+    pub fn checks(&self, book: &BookId) -> Option<&Checks> {
+        self.books.get(book)
+    }
+
+    /// Checks the book every `interval` instead of going by its status
+    pub fn set_interval(&mut self, book: &BookId, interval: Option<Duration>) {
+        let checks = self.books.entry(book.clone()).or_default();
+        checks.interval = interval;
+        checks.next = None;
+    }
+
+    pub fn interval(&self, book: &Book) -> Duration {
+        let checks = self.books.get(&book.id);
+        checks
+            .and_then(|c| c.interval)
+            .unwrap_or(match book.status {
+                BookStatus::Ongoing => self.intervals.ongoing,
+                BookStatus::Unknown => self.intervals.unknown,
+                BookStatus::Hiatus => self.intervals.hiatus,
+                BookStatus::Completed => self.intervals.completed,
+            })
+    }
+
+    /// When the book is to be checked next. Books never checked are due
+    /// right away, others an interval after the last check or shortly after
+    /// their next expected release, whichever comes first.
+    pub fn plan(&self, book: &Book) -> DateTime<Utc> {
+        let last = match self.books.get(&book.id).and_then(|c| c.last) {
+            Some(last) => last,
+            None => return chrono::MIN_DATETIME,
+        };
+        let interval = self.interval(book);
+        // Books with the same interval don't all come up at the same time
+        let mut hasher = DefaultHasher::new();
+        book.id.hash(&mut hasher);
+        let offset = interval.as_secs() / 20;
+        let offset = hasher.finish() % offset.max(1);
+        let regular = last + span(interval + Duration::from_secs(offset));
+        match Cadence::learn(book) {
+            Some(cadence) => {
+                regular.min(cadence.after(last) + Span::seconds(Cadence::MARGIN))
+            }
+            None => regular,
+        }
+    }
+
+    /// Books due for a check at `now`, at most one per domain, leaving the
+    /// domains checked less than `spacing` ago alone. Books whose host can't
+    /// be told aren't spaced at all.
+    pub fn due(&self, lib: &Library, now: DateTime<Utc>) -> Vec<BookId> {
+        let mut due: BTreeMap<String, (DateTime<Utc>, BookId)> = BTreeMap::new();
+        let mut hostless = vec![];
+        for (at, domain, id) in self.schedule(lib) {
+            if at > now {
+                continue;
+            }
+            let domain = match domain {
+                Some(domain) => domain,
+                None => {
+                    hostless.push(id);
+                    continue;
+                }
+            };
+            let earliest = due.get(&domain).map_or(true, |(t, _)| at < *t);
+            if earliest {
+                due.insert(domain, (at, id));
+            }
+        }
+        due.into_iter()
+            .map(|(_, (_, id))| id)
+            .chain(hostless)
+            .collect()
+    }
+
+    /// When the next book comes up, `None` for an empty library
+    pub fn next_due(&self, lib: &Library) -> Option<DateTime<Utc>> {
+        self.schedule(lib).into_iter().map(|(at, ..)| at).min()
+    }
+
+    /// Records a check of the book and plans the next one
+    pub fn checked(&mut self, book: &Book, at: DateTime<Utc>) {
+        self.books.entry(book.id.clone()).or_default().last = Some(at);
+        let next = self.plan(book);
+        self.books.get_mut(&book.id).map(|c| c.next = Some(next));
+    }
+
+    /// Forgets books no longer in the library
+    pub fn prune(&mut self, lib: &Library) {
+        self.books.retain(|id, _| lib.books.contains_key(id));
+    }
+
+    /// When each book with a source can be checked, along with its domain
+    fn schedule(
+        &self, lib: &Library,
+    ) -> Vec<(DateTime<Utc>, Option<String>, BookId)> {
+        let books = lib
+            .books
+            .values()
+            .filter(|b| !b.index.location.is_empty())
+            .map(|b| (b, b.index.domain()))
+            .collect::<Vec<_>>();
+        let mut domains: HashMap<&str, DateTime<Utc>> = HashMap::new();
+        for (book, domain) in &books {
+            let last = self.books.get(&book.id).and_then(|c| c.last);
+            if let (Some(domain), Some(last)) = (domain, last) {
+                let latest = domains.entry(domain.as_str()).or_insert(last);
+                *latest = (*latest).max(last);
+            }
+        }
+        let spacing = span(self.intervals.spacing);
+        books
+            .iter()
+            .map(|(book, domain)| {
+                let planned = self
+                    .books
+                    .get(&book.id)
+                    .and_then(|c| c.next)
+                    .unwrap_or_else(|| self.plan(book));
+                let free = domain
+                    .as_deref()
+                    .and_then(|d| domains.get(d))
+                    .map(|&d| d + spacing);
+                let at = free.map_or(planned, |free| planned.max(free));
+                (at, domain.clone(), book.id.clone())
+            })
+            .collect()
+    }
+}
+
+/// When the chapter came in, its earliest downloaded page or else the page
+/// listing them
+fn released(ch: &Chapter) -> Option<DateTime<Utc>> {
+    ch.pages()
+        .values()
+        .filter_map(|cnt| cnt.fetched)
+        .min()
+        .or_else(|| ch.page.page.as_ref()?.fetched)
+}
+
+fn span(duration: Duration) -> Span {
+    Span::from_std(duration).unwrap_or_else(|_| Span::max_value())
+}
+
+#[test]
+fn watch_plan() {
+    use crate::library::Content;
+
+    let mut lib = Library::default();
+    let mut book = Book::new(
+        "Weekly".to_string().into(),
+        "https://weekly.novel.test/book".to_string().into(),
+    );
+    book.status = BookStatus::Ongoing;
+    let start = Utc::now() - Span::days(30);
+    for n in 0..4 {
+        let mut ch = Chapter::default();
+        ch.page = format!("https://weekly.novel.test/chapter-{}", n + 1).into();
+        let mut cnt = Content::new(0, String::new());
+        cnt.fetched = Some(start + Span::days(7 * n));
+        ch.add_content(cnt);
+        book.chapters.insert(ch.num(), ch);
+    }
+    let cadence = Cadence::learn(&book).unwrap();
+    assert_eq!(cadence.period, Span::days(7));
+    let id = lib.insert(book).id.clone();
+    let other = Book::new(
+        "Other".to_string().into(),
+        "https://weekly.novel.test/other".to_string().into(),
+    );
+    lib.insert(other);
+
+    // Never checked, both are due but only one goes to the domain at a time
+    let mut watch = Watch::default();
+    let now = Utc::now();
+    assert_eq!(watch.due(&lib, now).len(), 1);
+    let book = &lib.books[&id];
+    watch.checked(book, now);
+    assert!(watch.due(&lib, now).is_empty());
+    let due = now + span(watch.intervals.spacing);
+    assert_eq!(watch.due(&lib, due).len(), 1);
+
+    // The next release is expected in 5 days, the regular check comes first
+    // unless the interval is longer than that
+    let next = watch.checks(&id).unwrap().next.unwrap();
+    assert!(next <= now + span(watch.intervals.ongoing) + Span::hours(1));
+    watch.set_interval(&id, Some(Duration::from_secs(30 * 24 * 3600)));
+    assert_eq!(watch.plan(book), cadence.after(now) + Span::minutes(15));
+
+    // A book on an IP host is spaced by its address
+    let ip = Book::new(
+        "Local".to_string().into(),
+        "http://127.0.0.1/book".to_string().into(),
+    );
+    let ip = lib.insert(ip).id.clone();
+    assert!(watch.due(&lib, now).contains(&ip));
+}